@@ -0,0 +1,183 @@
+use super::errors::EpubWriterError;
+use super::writelog::WriteLog;
+use super::{
+    EpubWriter, EpubWriterObserver, EpubWriterOptions, Metadata, PageOptions, PreparedImage,
+};
+use std::io::SeekFrom;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// `EpubWriter` counterpart for async code, writing to an `AsyncWrite + AsyncSeek` sink.
+///
+/// Book generation runs through the same code as `EpubWriter`, with identical validation
+/// and errors; output produced by each call is written to the sink before it returns,
+/// so no more than one image is held in memory at a time.
+///
+/// Probing, hashing and compression run on tokio's blocking thread pool, so they don't stall
+/// the executor. Dropping the future of a call before it completes makes later calls fail.
+pub struct AsyncEpubWriter<W: AsyncWrite + AsyncSeek + Unpin> {
+    writer: Option<EpubWriter<WriteLog>>,
+    log: WriteLog,
+    inner: W,
+    start_position: u64,
+}
+
+impl<W: AsyncWrite + AsyncSeek + Unpin> AsyncEpubWriter<W> {
+    pub async fn new(inner: W, metadata: Metadata) -> Result<AsyncEpubWriter<W>, EpubWriterError> {
+        return AsyncEpubWriter::new_with_options(inner, metadata, EpubWriterOptions::default())
+            .await;
+    }
+
+    pub async fn new_with_options(
+        mut inner: W,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<AsyncEpubWriter<W>, EpubWriterError> {
+        let start_position = inner.stream_position().await?;
+        let log = WriteLog::default();
+        let writer_log = log.clone();
+        let writer = AsyncEpubWriter::<W>::blocking(move || {
+            return EpubWriter::new_with_options(writer_log, metadata, options);
+        })
+        .await?;
+        let mut output = AsyncEpubWriter {
+            writer: Some(writer),
+            log,
+            inner,
+            start_position,
+        };
+
+        output.write_pending().await?;
+        return Ok(output);
+    }
+
+    /// Byte counts reported to the observer are those produced by the writer,
+    /// the sink may not have received all of them yet when events are delivered.
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        if let Some(d) = self.writer.as_mut() {
+            d.set_observer(observer);
+        }
+    }
+
+    pub async fn set_cover<T: AsyncRead + Unpin>(
+        &mut self,
+        image: &mut T,
+    ) -> Result<(), EpubWriterError> {
+        let image = AsyncEpubWriter::<W>::read_image(image).await?;
+        return self.set_prepared_cover(image).await;
+    }
+
+    pub async fn set_prepared_cover(
+        &mut self,
+        image: PreparedImage,
+    ) -> Result<(), EpubWriterError> {
+        return self.run(move |d| d.set_prepared_cover(image)).await;
+    }
+
+    pub async fn set_back_cover<T: AsyncRead + Unpin>(
+        &mut self,
+        image: &mut T,
+    ) -> Result<(), EpubWriterError> {
+        let image = AsyncEpubWriter::<W>::read_image(image).await?;
+        return self.set_prepared_back_cover(image).await;
+    }
+
+    pub async fn set_prepared_back_cover(
+        &mut self,
+        image: PreparedImage,
+    ) -> Result<(), EpubWriterError> {
+        return self.run(move |d| d.set_prepared_back_cover(image)).await;
+    }
+
+    pub async fn add_image<T: AsyncRead + Unpin>(
+        &mut self,
+        image: &mut T,
+        label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self
+            .add_image_with_options(
+                image,
+                PageOptions {
+                    nav_label: label,
+                    ..Default::default()
+                },
+            )
+            .await;
+    }
+
+    pub async fn add_image_with_options<T: AsyncRead + Unpin>(
+        &mut self,
+        image: &mut T,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        let image = AsyncEpubWriter::<W>::read_image(image).await?;
+        return self.add_prepared_image(image, options).await;
+    }
+
+    pub async fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self
+            .run(move |d| d.add_prepared_image(image, options))
+            .await;
+    }
+
+    pub async fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        let result = self.run(|d| d.finalize()).await;
+        self.inner.flush().await?;
+        return result;
+    }
+
+    async fn read_image<T: AsyncRead + Unpin>(
+        image: &mut T,
+    ) -> Result<PreparedImage, EpubWriterError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        image.read_to_end(&mut buffer).await?;
+        return AsyncEpubWriter::<W>::blocking(move || PreparedImage::new(buffer)).await;
+    }
+
+    /// Runs a call on the synchronous writer off the executor, then writes its output to the sink
+    async fn run<F>(&mut self, call: F) -> Result<(), EpubWriterError>
+    where
+        F: FnOnce(&mut EpubWriter<WriteLog>) -> Result<(), EpubWriterError> + Send + 'static,
+    {
+        let mut writer = match self.writer.take() {
+            Some(d) => d,
+            None => return Err(EpubWriterError::FinalizedError()),
+        };
+        let (writer, result) = AsyncEpubWriter::<W>::blocking(move || {
+            let result = call(&mut writer);
+            return Ok((writer, result));
+        })
+        .await?;
+        self.writer = Some(writer);
+
+        self.write_pending().await?;
+        return result;
+    }
+
+    async fn blocking<F, T>(call: F) -> Result<T, EpubWriterError>
+    where
+        F: FnOnce() -> Result<T, EpubWriterError> + Send + 'static,
+        T: Send + 'static,
+    {
+        return match tokio::task::spawn_blocking(call).await {
+            Ok(d) => d,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            Err(e) => Err(std::io::Error::other(e).into()),
+        };
+    }
+
+    /// Replays writes made by the synchronous writer onto the sink
+    async fn write_pending(&mut self) -> Result<(), EpubWriterError> {
+        for (offset, data) in self.log.take_pending() {
+            self.inner
+                .seek(SeekFrom::Start(self.start_position + offset))
+                .await?;
+            self.inner.write_all(&data).await?;
+        }
+
+        return Ok(());
+    }
+}
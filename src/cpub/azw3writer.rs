@@ -0,0 +1,1097 @@
+use super::bookwriter::BookWriter;
+use super::countingwriter::CountingWriter;
+use super::errors::EpubWriterError;
+use super::metadata::Metadata;
+use super::observer::{EpubWriterEvent, EpubWriterObserver, EventDispatcher, FinalizePhase};
+use super::pageoptions::PageOptions;
+use super::pagesequence::PageSequence;
+use super::pagetemplates::PageTemplates;
+use super::preparedimage::PreparedImage;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::prelude::*;
+
+const TEXT_RECORD_SIZE: usize = 4096;
+const NULL_INDEX: u32 = 0xFFFFFFFF;
+const BASE32_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUV";
+
+const INDEX_HEADER_LENGTH: usize = 192;
+const INDEX_RECORD_LIMIT: usize = 0x10000 - INDEX_HEADER_LENGTH - 1048;
+const CNCX_RECORD_LIMIT: usize = 0x10000 - 1024;
+
+const FLIS_RECORD: &[u8] = b"FLIS\x00\x00\x00\x08\x00\x41\x00\x00\x00\x00\x00\x00\xff\xff\xff\xff\x00\x01\x00\x03\x00\x00\x00\x03\x00\x00\x00\x01\xff\xff\xff\xff";
+const EOF_RECORD: &[u8] = b"\xe9\x8e\r\n";
+
+/// Text of all page documents, with their skeleton index entries and body fragments
+type BookText = (Vec<u8>, Vec<IndexEntry>, Vec<TextFragment>);
+
+// Tag tables as (tag number, values per entry, control byte mask, end of control byte flag)
+type TagDefinition = (u8, u8, u8, u8);
+const TAG_TABLE_END: TagDefinition = (0, 0, 0, 1);
+const SKELETON_INDEX_TAGS: [TagDefinition; 3] = [(1, 1, 3, 0), (6, 2, 12, 0), TAG_TABLE_END];
+const FRAGMENT_INDEX_TAGS: [TagDefinition; 5] = [
+    (2, 1, 1, 0),
+    (3, 1, 2, 0),
+    (4, 1, 4, 0),
+    (6, 2, 8, 0),
+    TAG_TABLE_END,
+];
+const NCX_INDEX_TAGS: [TagDefinition; 15] = [
+    (1, 1, 1, 0),
+    (2, 1, 2, 0),
+    (3, 1, 4, 0),
+    (4, 1, 8, 0),
+    (21, 1, 16, 0),
+    (22, 1, 32, 0),
+    (23, 1, 64, 0),
+    (6, 2, 128, 0),
+    TAG_TABLE_END,
+    (69, 1, 1, 0),
+    (70, 1, 2, 0),
+    (71, 1, 4, 0),
+    (72, 1, 8, 0),
+    (73, 1, 16, 0),
+    TAG_TABLE_END,
+];
+
+const EXTH_CREATOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_DESCRIPTION: u32 = 103;
+const EXTH_SUBJECT: u32 = 105;
+const EXTH_PUBLISHED_DATE: u32 = 106;
+const EXTH_RIGHTS: u32 = 109;
+const EXTH_SOURCE: u32 = 112;
+const EXTH_ASIN: u32 = 113;
+const EXTH_FIXED_LAYOUT: u32 = 122;
+const EXTH_BOOK_TYPE: u32 = 123;
+const EXTH_ORIENTATION_LOCK: u32 = 124;
+const EXTH_RESOURCE_COUNT: u32 = 125;
+const EXTH_ORIGINAL_RESOLUTION: u32 = 126;
+const EXTH_COVER_URI: u32 = 129;
+const EXTH_REGION_MAGNIFICATION: u32 = 132;
+const EXTH_COVER_OFFSET: u32 = 201;
+const EXTH_HAS_FAKE_COVER: u32 = 203;
+const EXTH_CDE_TYPE: u32 = 501;
+const EXTH_UPDATED_TITLE: u32 = 503;
+const EXTH_CDE_CONTENT_KEY: u32 = 504;
+const EXTH_LANGUAGE: u32 = 524;
+const EXTH_PRIMARY_WRITING_MODE: u32 = 525;
+const EXTH_PAGE_PROGRESSION_DIRECTION: u32 = 527;
+
+struct IndexEntry {
+    key: String,
+    values: Vec<(u8, Vec<u32>)>,
+}
+
+struct TextFragment {
+    insert_position: usize,
+    selector: String,
+    file_number: u32,
+    start_position: usize,
+    length: usize,
+}
+
+/// Writes fixed layout comic books in the KF8 (azw3) format used by Kindle devices.
+///
+/// Pages are laid out exactly as in the ePub output, spreads included, with image data
+/// stored unaltered as resource records. Since resources follow the text in a KF8 file,
+/// images are kept in memory until `finalize` is called.
+pub struct Azw3Writer<W: Write> {
+    metadata: Metadata,
+    pages: PageSequence,
+    resources: Vec<Vec<u8>>,
+    events: EventDispatcher,
+    finalized: bool,
+    inner: CountingWriter<W>,
+}
+
+impl<W: Write> Azw3Writer<W> {
+    pub fn new(inner: W, metadata: Metadata) -> Result<Azw3Writer<W>, EpubWriterError> {
+        metadata.validate()?;
+
+        let events = EventDispatcher::new();
+        let inner = CountingWriter::new(inner, events.bytes_written());
+        return Ok(Azw3Writer {
+            metadata,
+            pages: PageSequence::new(),
+            resources: Vec::default(),
+            events,
+            finalized: false,
+            inner,
+        });
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        self.events.set_observer(observer);
+    }
+
+    pub fn set_cover<T: std::io::Read>(&mut self, image: &mut T) -> Result<(), EpubWriterError> {
+        return self.set_prepared_cover(PreparedImage::read(image)?);
+    }
+
+    pub fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let img_filename = self.pages.set_cover(page_image)?.image_file_name();
+        // The cover is always the first resource, regardless of when it was set
+        self.resources.insert(0, buffer);
+
+        self.events.notify(EpubWriterEvent::CoverSet {
+            file_name: &img_filename,
+        });
+        return Ok(());
+    }
+
+    pub fn add_image<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_image_with_options(
+            image,
+            PageOptions {
+                nav_label: label,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn add_image_with_options<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(PreparedImage::read(image)?, options);
+    }
+
+    pub fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let page_image = self.pages.add_image(page_image, options)?;
+        let (spread, size) = (page_image.spread, page_image.size);
+        let img_filename = page_image.image_file_name();
+        self.resources.push(buffer);
+
+        let index = self.pages.images.len() - 1;
+        if spread {
+            self.events
+                .notify(EpubWriterEvent::SpreadDetected { index, size });
+        }
+        self.events.notify(EpubWriterEvent::PageAdded {
+            index,
+            file_name: &img_filename,
+        });
+        return Ok(());
+    }
+
+    pub fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Ok(());
+        }
+
+        self.finalized = true;
+        self.pages.validate()?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Started));
+        if self.pages.cover_spacer_required {
+            self.events.notify(EpubWriterEvent::SpacerInserted);
+        }
+
+        let (text, skeleton_entries, fragments) = self.generate_text()?;
+        let ncx_entries = self.generate_ncx_entries(&fragments, text.len());
+        let text_length = u32::try_from(text.len()).map_err(|_| EpubWriterError::Azw3SizeError)?;
+
+        let mut records = vec![Vec::<u8>::new()];
+        records.append(&mut generate_text_records(&text));
+        let first_non_text_record = records.len() as u32;
+
+        let fragment_index = records.len() as u32;
+        let mut cncx = Cncx::new();
+        let fragment_entries = fragments
+            .iter()
+            .enumerate()
+            .map(|(i, d)| IndexEntry {
+                key: format!("{:010}", d.insert_position),
+                values: vec![
+                    (2, vec![cncx.add(&d.selector)]),
+                    (3, vec![d.file_number]),
+                    (4, vec![i as u32]),
+                    (6, vec![d.start_position as u32, d.length as u32]),
+                ],
+            })
+            .collect::<Vec<_>>();
+        records.append(&mut generate_index_records(
+            &FRAGMENT_INDEX_TAGS,
+            1,
+            &fragment_entries,
+            cncx.into_records(),
+        ));
+
+        let skeleton_index = records.len() as u32;
+        records.append(&mut generate_index_records(
+            &SKELETON_INDEX_TAGS,
+            1,
+            &skeleton_entries,
+            Vec::new(),
+        ));
+
+        let ncx_index = records.len() as u32;
+        let mut cncx = Cncx::new();
+        let key_digits = usize::max(2, format!("{:X}", ncx_entries.len()).len());
+        let ncx_entries = ncx_entries
+            .into_iter()
+            .enumerate()
+            .map(
+                |(i, (label, position, length, fragment_number))| IndexEntry {
+                    key: format!("{:01$X}", i, key_digits),
+                    values: vec![
+                        (1, vec![position as u32]),
+                        (2, vec![length as u32]),
+                        (3, vec![cncx.add(&label)]),
+                        (4, vec![0]),
+                        (6, vec![fragment_number, 0]),
+                    ],
+                },
+            )
+            .collect::<Vec<_>>();
+        records.append(&mut generate_index_records(
+            &NCX_INDEX_TAGS,
+            2,
+            &ncx_entries,
+            cncx.into_records(),
+        ));
+        self.events.notify(EpubWriterEvent::Finalizing(
+            FinalizePhase::NavigationWritten,
+        ));
+
+        let first_resource_record = records.len() as u32;
+        records.append(&mut self.resources);
+
+        let fdst_record = records.len() as u32;
+        let mut fdst = b"FDST".to_vec();
+        fdst.extend_from_slice(&12u32.to_be_bytes());
+        fdst.extend_from_slice(&1u32.to_be_bytes());
+        fdst.extend_from_slice(&0u32.to_be_bytes());
+        fdst.extend_from_slice(&text_length.to_be_bytes());
+        records.push(fdst);
+
+        let flis_record = records.len() as u32;
+        records.push(FLIS_RECORD.to_vec());
+
+        let fcis_record = records.len() as u32;
+        let mut fcis =
+            b"FCIS\x00\x00\x00\x14\x00\x00\x00\x10\x00\x00\x00\x02\x00\x00\x00\x00".to_vec();
+        fcis.extend_from_slice(&text_length.to_be_bytes());
+        fcis.extend_from_slice(b"\x00\x00\x00\x00\x00\x00\x00\x28\x00\x00\x00\x00\x00\x00\x00\x28\x00\x00\x00\x08\x00\x01\x00\x01\x00\x00\x00\x00");
+        records.push(fcis);
+
+        records.push(EOF_RECORD.to_vec());
+
+        let header = MobiHeader {
+            text_length,
+            text_record_count: u16::try_from(first_non_text_record - 1)
+                .map_err(|_| EpubWriterError::Azw3SizeError)?,
+            first_non_text_record,
+            first_resource_record,
+            fdst_record,
+            flis_record,
+            fcis_record,
+            ncx_index,
+            fragment_index,
+            skeleton_index,
+        };
+        records[0] = self.generate_record0(&header);
+        // Checked before writing anything, as the format cannot address larger books
+        let offsets = record_offsets(&records)?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::MetadataWritten));
+
+        self.write_database(&records, &offsets)?;
+        self.inner.flush()?;
+
+        self.events.notify_bytes_written();
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Completed));
+        return Ok(());
+    }
+
+    fn generate_text(&self) -> Result<BookText, EpubWriterError> {
+        let (metadata, templates) = (&self.metadata, &PageTemplates::default());
+        let mut documents = Vec::<String>::new();
+
+        let cover = self.pages.cover.as_ref().unwrap();
+        let uri = embed_uri(0, cover.mime_type);
+        for (_, content) in cover.generate_pages_xml_with_href(metadata, templates, &uri) {
+            documents.push(content);
+        }
+        if self.pages.cover_spacer_required {
+            documents.push(cover.generate_spacer_page(metadata, templates).1);
+        }
+        for (i, page_image) in self.pages.images.iter().enumerate() {
+            let uri = embed_uri(i + 1, page_image.mime_type);
+            for (_, content) in page_image.generate_pages_xml_with_href(metadata, templates, &uri) {
+                documents.push(content);
+            }
+        }
+
+        // Each page document is split into a skeleton, holding everything but the contents
+        // of its body, and a single fragment with said contents, following it in the text.
+        let mut text = Vec::<u8>::new();
+        let mut skeleton_entries = Vec::<IndexEntry>::new();
+        let mut fragments = Vec::<TextFragment>::new();
+        for (i, document) in documents.iter().enumerate() {
+            let aid = to_base32(i as u32, 1);
+            let (body_start, body_end) = match find_body(document) {
+                Some(d) => d,
+                None => return Err(EpubWriterError::Azw3PageDocumentError { file_number: i }),
+            };
+
+            let skeleton_head = format!("{} aid=\"{}\">", &document[..body_start - 1], &aid);
+            let skeleton = format!("{}{}", skeleton_head, &document[body_end..]);
+            let fragment = &document[body_start..body_end];
+
+            let skeleton_position = text.len();
+            text.extend_from_slice(skeleton.as_bytes());
+            text.extend_from_slice(fragment.as_bytes());
+
+            skeleton_entries.push(IndexEntry {
+                key: format!("SKEL{:010}", i),
+                values: vec![
+                    (1, vec![1, 1]),
+                    (
+                        6,
+                        vec![
+                            skeleton_position as u32,
+                            skeleton.len() as u32,
+                            skeleton_position as u32,
+                            skeleton.len() as u32,
+                        ],
+                    ),
+                ],
+            });
+            fragments.push(TextFragment {
+                insert_position: skeleton_position + skeleton_head.len(),
+                selector: format!("P-//*[@aid=\"{}\"]", &aid),
+                file_number: i as u32,
+                start_position: 0,
+                length: fragment.len(),
+            });
+        }
+
+        return Ok((text, skeleton_entries, fragments));
+    }
+
+    fn generate_ncx_entries(
+        &self,
+        fragments: &[TextFragment],
+        text_length: usize,
+    ) -> Vec<(String, usize, usize, u32)> {
+        let spine = self.pages.spine_file_names(self.metadata.right_to_left);
+        let mut targets = self
+            .pages
+            .images
+            .iter()
+            .filter(|&d| d.nav_label.is_some())
+            .map(|d| {
+                let file_name = d.page_file_names(self.metadata.right_to_left).remove(0);
+                let file_number = spine.iter().position(|e| *e == file_name).unwrap();
+                (d.nav_label.clone().unwrap(), file_number)
+            })
+            .collect::<Vec<_>>();
+        if targets.is_empty() {
+            targets.push((self.metadata.title.clone(), 0));
+        }
+
+        let mut output = Vec::<(String, usize, usize, u32)>::new();
+        for (i, (label, file_number)) in targets.iter().enumerate() {
+            let position = fragments[*file_number].insert_position;
+            let end = match targets.get(i + 1) {
+                Some((_, d)) => fragments[*d].insert_position,
+                None => text_length,
+            };
+            output.push((label.clone(), position, end - position, *file_number as u32));
+        }
+
+        return output;
+    }
+
+    fn generate_record0(&self, header: &MobiHeader) -> Vec<u8> {
+        fn push_u32(buffer: &mut Vec<u8>, value: u32) {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let exth = self.generate_exth();
+        let title = self.metadata.title.as_bytes();
+
+        let mut output = Vec::<u8>::new();
+
+        // PalmDOC header, text is stored uncompressed
+        output.extend_from_slice(&1u16.to_be_bytes());
+        output.extend_from_slice(&0u16.to_be_bytes());
+        push_u32(&mut output, header.text_length);
+        output.extend_from_slice(&header.text_record_count.to_be_bytes());
+        output.extend_from_slice(&(TEXT_RECORD_SIZE as u16).to_be_bytes());
+        output.extend_from_slice(&0u32.to_be_bytes());
+
+        // MOBI header
+        output.extend_from_slice(b"MOBI");
+        push_u32(&mut output, 264);
+        push_u32(&mut output, 2);
+        push_u32(&mut output, 65001);
+        push_u32(&mut output, self.unique_id());
+        push_u32(&mut output, 8);
+        for _ in 0..10 {
+            push_u32(&mut output, NULL_INDEX);
+        }
+        push_u32(&mut output, header.first_non_text_record);
+        push_u32(&mut output, (16 + 264 + exth.len()) as u32);
+        push_u32(&mut output, title.len() as u32);
+        push_u32(&mut output, language_code(&self.metadata.language));
+        push_u32(&mut output, 0);
+        push_u32(&mut output, 0);
+        push_u32(&mut output, 8);
+        push_u32(&mut output, header.first_resource_record);
+        output.extend_from_slice(&[0u8; 16]);
+        push_u32(&mut output, 0x50);
+        output.extend_from_slice(&[0u8; 32]);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, NULL_INDEX);
+        output.extend_from_slice(&[0u8; 12]);
+        output.extend_from_slice(&[0u8; 8]);
+        push_u32(&mut output, header.fdst_record);
+        push_u32(&mut output, 1);
+        push_u32(&mut output, header.fcis_record);
+        push_u32(&mut output, 1);
+        push_u32(&mut output, header.flis_record);
+        push_u32(&mut output, 1);
+        output.extend_from_slice(&[0u8; 8]);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, 0);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, 1);
+        push_u32(&mut output, header.ncx_index);
+        push_u32(&mut output, header.fragment_index);
+        push_u32(&mut output, header.skeleton_index);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, 0);
+        push_u32(&mut output, NULL_INDEX);
+        push_u32(&mut output, 0);
+
+        output.extend_from_slice(&exth);
+        output.extend_from_slice(title);
+        // Padding reserved for data added by Amazon's own tools
+        output.extend_from_slice(&[0u8; 8192]);
+        output.resize(output.len() + (4 - output.len() % 4) % 4, 0);
+
+        return output;
+    }
+
+    fn generate_exth(&self) -> Vec<u8> {
+        let cover = self.pages.cover.as_ref().unwrap();
+        let resolution = format!("{}x{}", cover.size.0, cover.size.1);
+
+        let mut records: Vec<(u32, Vec<u8>)> = vec![
+            (EXTH_CREATOR, self.metadata.author.as_bytes().to_vec()),
+            (EXTH_PUBLISHER, self.metadata.publisher.as_bytes().to_vec()),
+            (
+                EXTH_PUBLISHED_DATE,
+                self.metadata
+                    .published_date
+                    .to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+                    .into_bytes(),
+            ),
+            (EXTH_ASIN, self.metadata.id.as_bytes().to_vec()),
+            (EXTH_CDE_CONTENT_KEY, self.metadata.id.as_bytes().to_vec()),
+            (EXTH_CDE_TYPE, b"EBOK".to_vec()),
+            (EXTH_UPDATED_TITLE, self.metadata.title.as_bytes().to_vec()),
+            (EXTH_LANGUAGE, self.metadata.language.as_bytes().to_vec()),
+            (EXTH_FIXED_LAYOUT, b"true".to_vec()),
+            (EXTH_BOOK_TYPE, b"comic".to_vec()),
+            (EXTH_ORIENTATION_LOCK, b"none".to_vec()),
+            (EXTH_ORIGINAL_RESOLUTION, resolution.into_bytes()),
+            (EXTH_REGION_MAGNIFICATION, b"false".to_vec()),
+            (EXTH_COVER_URI, embed_uri(0, cover.mime_type).into_bytes()),
+            (EXTH_COVER_OFFSET, 0u32.to_be_bytes().to_vec()),
+            (EXTH_HAS_FAKE_COVER, 0u32.to_be_bytes().to_vec()),
+            (
+                EXTH_RESOURCE_COUNT,
+                (self.pages.images.len() as u32 + 1).to_be_bytes().to_vec(),
+            ),
+        ];
+
+        if self.metadata.right_to_left {
+            records.push((EXTH_PAGE_PROGRESSION_DIRECTION, b"rtl".to_vec()));
+            records.push((EXTH_PRIMARY_WRITING_MODE, b"horizontal-rl".to_vec()));
+        } else {
+            records.push((EXTH_PAGE_PROGRESSION_DIRECTION, b"ltr".to_vec()));
+        }
+        if let Some(d) = self.metadata.description.as_ref() {
+            records.push((EXTH_DESCRIPTION, d.as_bytes().to_vec()));
+        }
+        for i in self.metadata.tags.iter() {
+            records.push((EXTH_SUBJECT, i.as_bytes().to_vec()));
+        }
+        if let Some(d) = self.metadata.source.as_ref() {
+            records.push((EXTH_SOURCE, d.as_bytes().to_vec()));
+        }
+        if let Some(d) = self.metadata.copyright.as_ref() {
+            records.push((EXTH_RIGHTS, d.as_bytes().to_vec()));
+        }
+
+        let mut data = Vec::<u8>::new();
+        for (record_type, record_data) in records.iter() {
+            data.extend_from_slice(&record_type.to_be_bytes());
+            data.extend_from_slice(&(record_data.len() as u32 + 8).to_be_bytes());
+            data.extend_from_slice(record_data);
+        }
+
+        let mut output = b"EXTH".to_vec();
+        output.extend_from_slice(&(data.len() as u32 + 12).to_be_bytes());
+        output.extend_from_slice(&(records.len() as u32).to_be_bytes());
+        output.append(&mut data);
+        output.resize(output.len() + (4 - output.len() % 4) % 4, 0);
+
+        return output;
+    }
+
+    fn write_database(
+        &mut self,
+        records: &[Vec<u8>],
+        offsets: &[u32],
+    ) -> Result<(), EpubWriterError> {
+        let timestamp = chrono::Utc::now().timestamp() as u32;
+
+        let mut name = self
+            .metadata
+            .title
+            .chars()
+            .map(|d| match d {
+                d if d.is_ascii_alphanumeric() => d,
+                _ => '_',
+            })
+            .take(31)
+            .collect::<String>()
+            .into_bytes();
+        name.resize(32, 0);
+
+        self.inner.write_all(&name)?;
+        self.inner.write_all(&0u16.to_be_bytes())?;
+        self.inner.write_all(&0u16.to_be_bytes())?;
+        self.inner.write_all(&timestamp.to_be_bytes())?;
+        self.inner.write_all(&timestamp.to_be_bytes())?;
+        self.inner.write_all(&[0u8; 16])?;
+        self.inner.write_all(b"BOOKMOBI")?;
+        self.inner
+            .write_all(&(2 * records.len() as u32 - 1).to_be_bytes())?;
+        self.inner.write_all(&0u32.to_be_bytes())?;
+        self.inner
+            .write_all(&(records.len() as u16).to_be_bytes())?;
+
+        for (i, offset) in offsets.iter().enumerate() {
+            self.inner.write_all(&offset.to_be_bytes())?;
+            self.inner.write_all(&(2 * i as u32).to_be_bytes())?;
+        }
+        self.inner.write_all(&[0u8; 2])?;
+
+        for record in records.iter() {
+            self.inner.write_all(record)?;
+        }
+
+        return Ok(());
+    }
+
+    /// Derived from the book ID, so that it stays the same across builds
+    fn unique_id(&self) -> u32 {
+        let digest = Sha256::digest(self.metadata.id.as_bytes());
+        return u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+    }
+}
+
+impl<W: Write> BookWriter for Azw3Writer<W> {
+    fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        Azw3Writer::set_observer(self, observer);
+    }
+
+    fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return Azw3Writer::set_prepared_cover(self, image);
+    }
+
+    fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return Azw3Writer::add_prepared_image(self, image, options);
+    }
+
+    fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        return Azw3Writer::finalize(self);
+    }
+}
+
+struct MobiHeader {
+    text_length: u32,
+    text_record_count: u16,
+    first_non_text_record: u32,
+    first_resource_record: u32,
+    fdst_record: u32,
+    flis_record: u32,
+    fcis_record: u32,
+    ncx_index: u32,
+    fragment_index: u32,
+    skeleton_index: u32,
+}
+
+/// String table shared by the entries of an index
+struct Cncx {
+    offsets: HashMap<String, u32>,
+    records: Vec<Vec<u8>>,
+}
+
+impl Cncx {
+    fn new() -> Cncx {
+        Cncx {
+            offsets: HashMap::new(),
+            records: vec![Vec::new()],
+        }
+    }
+
+    fn add(&mut self, value: &str) -> u32 {
+        if let Some(d) = self.offsets.get(value) {
+            return *d;
+        }
+
+        let mut data = encode_vwi(value.len() as u32);
+        data.extend_from_slice(value.as_bytes());
+        if self.records.last().unwrap().len() + data.len() > CNCX_RECORD_LIMIT {
+            self.records.push(Vec::new());
+        }
+
+        let record_number = self.records.len() - 1;
+        let record = self.records.last_mut().unwrap();
+        let offset = (record_number * 0x10000 + record.len()) as u32;
+        record.append(&mut data);
+        self.offsets.insert(value.to_string(), offset);
+        return offset;
+    }
+
+    fn into_records(self) -> Vec<Vec<u8>> {
+        return self
+            .records
+            .into_iter()
+            .filter(|d| !d.is_empty())
+            .map(align_block)
+            .collect();
+    }
+}
+
+/// Positions right after the opening body tag and of the closing one
+fn find_body(document: &str) -> Option<(usize, usize)> {
+    let body_tag = document.find("<body")?;
+    let body_start = body_tag + document[body_tag..].find('>')? + 1;
+    let body_end = document.rfind("</body>")?;
+    if body_end < body_start {
+        return None;
+    }
+
+    return Some((body_start, body_end));
+}
+
+/// Offsets of records from the start of the database, failing if the record count or
+/// the database size do not fit the PDB header
+fn record_offsets(records: &[Vec<u8>]) -> Result<Vec<u32>, EpubWriterError> {
+    let record_count = u16::try_from(records.len()).map_err(|_| EpubWriterError::Azw3SizeError)?;
+    let mut offset = 78 + 8 * record_count as u32 + 2;
+    let mut output = Vec::<u32>::with_capacity(records.len());
+    for record in records.iter() {
+        output.push(offset);
+        offset = u32::try_from(record.len())
+            .ok()
+            .and_then(|d| offset.checked_add(d))
+            .ok_or(EpubWriterError::Azw3SizeError)?;
+    }
+
+    return Ok(output);
+}
+
+fn generate_text_records(text: &[u8]) -> Vec<Vec<u8>> {
+    let mut output = Vec::<Vec<u8>>::new();
+    for (i, chunk) in text.chunks(TEXT_RECORD_SIZE).enumerate() {
+        let mut record = chunk.to_vec();
+
+        // Characters split across records have their remaining bytes repeated
+        // as a multibyte trailing entry
+        let next = &text[((i + 1) * TEXT_RECORD_SIZE).min(text.len())..];
+        let overlap = next
+            .iter()
+            .take(3)
+            .take_while(|&d| d & 0xC0 == 0x80)
+            .count();
+        record.extend_from_slice(&next[..overlap]);
+        record.push(overlap as u8);
+        output.push(record);
+    }
+
+    return output;
+}
+
+fn generate_index_records(
+    tags: &[TagDefinition],
+    control_byte_count: u32,
+    entries: &[IndexEntry],
+    cncx_records: Vec<Vec<u8>>,
+) -> Vec<Vec<u8>> {
+    fn index_record_header(idxt_offset: usize, entry_count: usize) -> Vec<u8> {
+        let mut output = b"INDX".to_vec();
+        output.extend_from_slice(&(INDEX_HEADER_LENGTH as u32).to_be_bytes());
+        output.extend_from_slice(&0u32.to_be_bytes());
+        output.extend_from_slice(&1u32.to_be_bytes());
+        output.extend_from_slice(&0u32.to_be_bytes());
+        output.extend_from_slice(&(idxt_offset as u32).to_be_bytes());
+        output.extend_from_slice(&(entry_count as u32).to_be_bytes());
+        output.extend_from_slice(&[0xFFu8; 8]);
+        output.resize(INDEX_HEADER_LENGTH, 0);
+        return output;
+    }
+
+    // Entries are packed in as many records as needed, each followed by its IDXT table
+    let mut blocks = vec![(Vec::<u8>::new(), Vec::<u16>::new(), String::new())];
+    for entry in entries.iter() {
+        let mut data = vec![entry.key.len() as u8];
+        data.extend_from_slice(entry.key.as_bytes());
+        data.append(&mut control_bytes(tags, entry));
+        for (number, _, _, end_flag) in tags.iter() {
+            if *end_flag == 1 {
+                continue;
+            }
+            if let Some((_, values)) = entry.values.iter().find(|(d, _)| d == number) {
+                for value in values.iter() {
+                    data.append(&mut encode_vwi(*value));
+                }
+            }
+        }
+
+        let (block, idxt, _) = blocks.last().unwrap();
+        if block.len() + 2 * idxt.len() + data.len() + 2 > INDEX_RECORD_LIMIT {
+            blocks.push((Vec::new(), Vec::new(), String::new()));
+        }
+
+        let (block, idxt, last_key) = blocks.last_mut().unwrap();
+        idxt.push((INDEX_HEADER_LENGTH + block.len()) as u16);
+        block.append(&mut data);
+        *last_key = entry.key.clone();
+    }
+
+    let mut records = Vec::<Vec<u8>>::new();
+    for (block, idxt, _) in blocks.iter() {
+        let block = align_block(block.clone());
+        let mut idxt_block = b"IDXT".to_vec();
+        for i in idxt.iter() {
+            idxt_block.extend_from_slice(&i.to_be_bytes());
+        }
+
+        let mut record = index_record_header(INDEX_HEADER_LENGTH + block.len(), idxt.len());
+        record.extend_from_slice(&block);
+        record.append(&mut align_block(idxt_block));
+        records.push(record);
+    }
+
+    let mut tagx = b"TAGX".to_vec();
+    tagx.extend_from_slice(&(12 + 4 * tags.len() as u32).to_be_bytes());
+    tagx.extend_from_slice(&control_byte_count.to_be_bytes());
+    for (number, values_per_entry, mask, end_flag) in tags.iter() {
+        tagx.extend_from_slice(&[*number, *values_per_entry, *mask, *end_flag]);
+    }
+    let tagx = align_block(tagx);
+
+    // The header record describes the geometry of the data records, one entry per record
+    let mut geometry = Vec::<u8>::new();
+    let mut idxt_block = b"IDXT".to_vec();
+    for (_, idxt, last_key) in blocks.iter() {
+        idxt_block.extend_from_slice(
+            &((INDEX_HEADER_LENGTH + tagx.len() + geometry.len()) as u16).to_be_bytes(),
+        );
+        geometry.push(last_key.len() as u8);
+        geometry.extend_from_slice(last_key.as_bytes());
+        geometry.extend_from_slice(&(idxt.len() as u16).to_be_bytes());
+    }
+    let geometry = align_block(geometry);
+
+    let mut header = b"INDX".to_vec();
+    header.extend_from_slice(&(INDEX_HEADER_LENGTH as u32).to_be_bytes());
+    header.extend_from_slice(&[0u8; 12]);
+    header.extend_from_slice(
+        &((INDEX_HEADER_LENGTH + tagx.len() + geometry.len()) as u32).to_be_bytes(),
+    );
+    header.extend_from_slice(&(records.len() as u32).to_be_bytes());
+    header.extend_from_slice(&65001u32.to_be_bytes());
+    header.extend_from_slice(&NULL_INDEX.to_be_bytes());
+    header.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    header.extend_from_slice(&[0u8; 12]);
+    header.extend_from_slice(&(cncx_records.len() as u32).to_be_bytes());
+    header.resize(180, 0);
+    header.extend_from_slice(&(INDEX_HEADER_LENGTH as u32).to_be_bytes());
+    header.resize(INDEX_HEADER_LENGTH, 0);
+    header.extend_from_slice(&tagx);
+    header.extend_from_slice(&geometry);
+    header.append(&mut align_block(idxt_block));
+
+    let mut output = vec![header];
+    output.append(&mut records);
+    output.extend(cncx_records);
+    return output;
+}
+
+fn control_bytes(tags: &[TagDefinition], entry: &IndexEntry) -> Vec<u8> {
+    let mut output = Vec::<u8>::new();
+    let mut current = 0u8;
+    for (number, values_per_entry, mask, end_flag) in tags.iter() {
+        if *end_flag == 1 {
+            output.push(current);
+            current = 0;
+            continue;
+        }
+
+        if let Some((_, values)) = entry.values.iter().find(|(d, _)| d == number) {
+            let count = (values.len() / *values_per_entry as usize) as u8;
+            current |= mask & (count << mask.trailing_zeros());
+        }
+    }
+
+    return output;
+}
+
+/// Forward encoded variable width integer: 7 bits per byte, high bit set on the last one
+fn encode_vwi(value: u32) -> Vec<u8> {
+    let mut output = vec![(value & 0x7F) as u8 | 0x80];
+    let mut value = value >> 7;
+    while value > 0 {
+        output.insert(0, (value & 0x7F) as u8);
+        value >>= 7;
+    }
+
+    return output;
+}
+
+fn align_block(mut data: Vec<u8>) -> Vec<u8> {
+    data.resize(data.len() + (4 - data.len() % 4) % 4, 0);
+    return data;
+}
+
+fn to_base32(value: u32, min_digits: usize) -> String {
+    let mut output = Vec::<u8>::new();
+    let mut value = value;
+    loop {
+        output.insert(0, BASE32_DIGITS[(value % 32) as usize]);
+        value /= 32;
+        if value == 0 {
+            break;
+        }
+    }
+
+    while output.len() < min_digits {
+        output.insert(0, b'0');
+    }
+
+    return String::from_utf8(output).unwrap();
+}
+
+fn embed_uri(resource_index: usize, mime_type: &str) -> String {
+    format!(
+        "kindle:embed:{}?mime={}",
+        to_base32(resource_index as u32 + 1, 4),
+        mime_type
+    )
+}
+
+fn language_code(language: &str) -> u32 {
+    let primary = language.split('-').next().unwrap_or("").to_lowercase();
+    match primary.as_str() {
+        "ar" => 0x01,
+        "zh" => 0x04,
+        "cs" => 0x05,
+        "da" => 0x06,
+        "de" => 0x07,
+        "el" => 0x08,
+        "en" => 0x09,
+        "es" => 0x0a,
+        "fi" => 0x0b,
+        "fr" => 0x0c,
+        "he" => 0x0d,
+        "hu" => 0x0e,
+        "it" => 0x10,
+        "ja" => 0x11,
+        "ko" => 0x12,
+        "nl" => 0x13,
+        "nb" | "no" => 0x14,
+        "pl" => 0x15,
+        "pt" => 0x16,
+        "ru" => 0x19,
+        "sv" => 0x1d,
+        "tr" => 0x1f,
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    /// PNG image filled with a single shade, so that images differ by shade
+    fn png(width: u32, height: u32, shade: u8) -> Vec<u8> {
+        let image = RgbImage::from_pixel(width, height, Rgb([shade, shade, shade]));
+        let mut output = Vec::<u8>::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut output), ImageOutputFormat::Png)
+            .unwrap();
+        return output;
+    }
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        return u16::from_be_bytes(data[offset..offset + 2].try_into().unwrap());
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        return u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap());
+    }
+
+    /// Records of a PDB database, checking that their offsets follow each other
+    fn read_records(data: &[u8]) -> Vec<&[u8]> {
+        assert_eq!(&data[60..68], b"BOOKMOBI");
+        let record_count = read_u16(data, 76) as usize;
+        let offsets = (0..record_count)
+            .map(|d| read_u32(data, 78 + 8 * d) as usize)
+            .collect::<Vec<_>>();
+        assert_eq!(offsets[0], 78 + 8 * record_count + 2);
+
+        let mut output = Vec::<&[u8]>::new();
+        for (i, start) in offsets.iter().enumerate() {
+            let end = offsets.get(i + 1).cloned().unwrap_or(data.len());
+            assert!(*start <= end);
+            output.push(&data[*start..end]);
+        }
+        return output;
+    }
+
+    /// Values of EXTH records by type
+    fn read_exth(record0: &[u8]) -> HashMap<u32, Vec<u8>> {
+        let start = 16 + read_u32(record0, 20) as usize;
+        assert_eq!(&record0[start..start + 4], b"EXTH");
+        let mut output = HashMap::new();
+        let mut offset = start + 12;
+        for _ in 0..read_u32(record0, start + 8) {
+            let length = read_u32(record0, offset + 4) as usize;
+            output.insert(
+                read_u32(record0, offset),
+                record0[offset + 8..offset + length].to_vec(),
+            );
+            offset += length;
+        }
+        return output;
+    }
+
+    #[test]
+    fn writes_consistent_database() {
+        let images = [png(2, 3, 10), png(2, 3, 20), png(6, 3, 30), png(2, 3, 40)];
+        let mut output = Vec::<u8>::new();
+        let mut writer = Azw3Writer::new(&mut output, Metadata::default()).unwrap();
+        writer
+            .add_image(&mut images[1].as_slice(), Some("One".to_string()))
+            .unwrap();
+        writer.add_image(&mut images[2].as_slice(), None).unwrap();
+        writer
+            .add_image(&mut images[3].as_slice(), Some("Two".to_string()))
+            .unwrap();
+        // Set last, the cover still comes first among resources
+        writer.set_cover(&mut images[0].as_slice()).unwrap();
+        writer.finalize().unwrap();
+
+        let records = read_records(&output);
+        let record0 = records[0];
+        assert_eq!(&record0[16..20], b"MOBI");
+        let first_non_text_record = read_u32(record0, 80) as usize;
+        let first_resource_record = read_u32(record0, 108) as usize;
+        let fdst_record = read_u32(record0, 192) as usize;
+        let fcis_record = read_u32(record0, 200) as usize;
+        let flis_record = read_u32(record0, 208) as usize;
+        let ncx_index = read_u32(record0, 244) as usize;
+        let fragment_index = read_u32(record0, 248) as usize;
+        let skeleton_index = read_u32(record0, 252) as usize;
+
+        // Text records come right after the header record, then the indices
+        let text_record_count = read_u16(record0, 8) as usize;
+        assert_eq!(first_non_text_record, text_record_count + 1);
+        assert_eq!(fragment_index, first_non_text_record);
+        let mut text = Vec::<u8>::new();
+        for record in records[1..first_non_text_record].iter() {
+            let trailing = *record.last().unwrap() as usize + 1;
+            text.extend_from_slice(&record[..record.len() - trailing]);
+        }
+        assert_eq!(text.len(), read_u32(record0, 4) as usize);
+
+        // Cover, spacer before the spread, then three pages one of which a spread
+        let documents_number = 6;
+        let ncx_entries = 2;
+        for (index, entry_count) in [
+            (fragment_index, documents_number),
+            (skeleton_index, documents_number),
+            (ncx_index, ncx_entries),
+        ] {
+            assert_eq!(&records[index][..4], b"INDX");
+            assert_eq!(read_u32(records[index], 36), entry_count);
+            assert_eq!(&records[index + 1][..4], b"INDX");
+        }
+        assert!(fragment_index < skeleton_index && skeleton_index < ncx_index);
+        assert!(ncx_index < first_resource_record);
+
+        for (i, image) in images.iter().enumerate() {
+            assert_eq!(records[first_resource_record + i], image.as_slice());
+        }
+        assert_eq!(fdst_record, first_resource_record + images.len());
+        assert_eq!(&records[fdst_record][..4], b"FDST");
+        assert_eq!(read_u32(records[fdst_record], 16), text.len() as u32);
+        assert_eq!(&records[flis_record][..4], b"FLIS");
+        assert_eq!(&records[fcis_record][..4], b"FCIS");
+        assert_eq!(*records.last().unwrap(), EOF_RECORD);
+        assert_eq!(records.len(), fcis_record + 2);
+
+        // Resources are numbered from 1 in the order they are stored
+        let text = String::from_utf8(text).unwrap();
+        let positions = (1..=images.len())
+            .map(|d| text.find(&format!("kindle:embed:{:04}?mime=image/png", d)))
+            .collect::<Vec<_>>();
+        assert!(positions.iter().all(|d| d.is_some()));
+        assert!(positions.windows(2).all(|d| d[0] < d[1]));
+        assert!(!text.contains("kindle:embed:0005"));
+
+        let exth = read_exth(record0);
+        assert_eq!(exth[&EXTH_COVER_URI], b"kindle:embed:0001?mime=image/png");
+        assert_eq!(exth[&EXTH_COVER_OFFSET], 0u32.to_be_bytes());
+        assert_eq!(exth[&EXTH_RESOURCE_COUNT], 4u32.to_be_bytes());
+    }
+
+    #[test]
+    fn record_offsets_reject_oversized_databases() {
+        let records = vec![Vec::<u8>::new(); u16::MAX as usize + 1];
+        assert!(matches!(
+            record_offsets(&records),
+            Err(EpubWriterError::Azw3SizeError)
+        ));
+        assert_eq!(record_offsets(&records[..2]).unwrap(), [96, 96]);
+    }
+}
@@ -0,0 +1,57 @@
+use super::errors::EpubWriterError;
+use super::observer::EpubWriterObserver;
+use super::pageoptions::PageOptions;
+use super::preparedimage::PreparedImage;
+use std::io::Read;
+
+/// Output format independent interface to the book writers, allowing the same
+/// sequence of `set_cover`/`add_image`/`finalize` calls to produce any of them.
+pub trait BookWriter {
+    fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>);
+
+    fn set_cover(&mut self, mut image: &mut dyn Read) -> Result<(), EpubWriterError> {
+        return self.set_prepared_cover(PreparedImage::read(&mut image)?);
+    }
+
+    fn add_image(
+        &mut self,
+        image: &mut dyn Read,
+        label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_image_with_options(
+            image,
+            PageOptions {
+                nav_label: label,
+                ..Default::default()
+            },
+        );
+    }
+
+    fn add_image_with_options(
+        &mut self,
+        mut image: &mut dyn Read,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(PreparedImage::read(&mut image)?, options);
+    }
+
+    fn set_back_cover(&mut self, mut image: &mut dyn Read) -> Result<(), EpubWriterError> {
+        return self.set_prepared_back_cover(PreparedImage::read(&mut image)?);
+    }
+
+    fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError>;
+
+    /// Formats without a notion of back cover add it as a regular page,
+    /// so it has to be set after all other pages have been added.
+    fn set_prepared_back_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(image, PageOptions::default());
+    }
+
+    fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError>;
+
+    fn finalize(&mut self) -> Result<(), EpubWriterError>;
+}
@@ -0,0 +1,303 @@
+use super::bookwriter::BookWriter;
+use super::errors::EpubWriterError;
+use super::metadata::Metadata;
+use super::observer::{EpubWriterEvent, EpubWriterObserver, EventDispatcher, FinalizePhase};
+use super::pageimage::PageImage;
+use super::pageoptions::PageOptions;
+use super::pagesequence::PageSequence;
+use super::preparedimage::PreparedImage;
+use super::zipoutput::ZipOutput;
+use chrono::Datelike;
+use std::io::{prelude::*, Cursor};
+use xml::writer::XmlEvent;
+use xml::{EmitterConfig, EventWriter};
+
+/// Writes comic book archives: page images, named so that they sort in reading order,
+/// followed by a `ComicInfo.xml` file holding metadata.
+///
+/// Spreads are stored as single wide images, since comic readers handle those natively.
+pub struct CbzWriter<W: Write> {
+    metadata: Metadata,
+    pages: PageSequence,
+    events: EventDispatcher,
+    finalized: bool,
+    inner: ZipOutput<W>,
+}
+
+impl<W: Write + Seek> CbzWriter<W> {
+    pub fn new(inner: W, metadata: Metadata) -> Result<CbzWriter<W>, EpubWriterError> {
+        let events = EventDispatcher::new();
+        let inner = ZipOutput::new_seekable(inner, events.bytes_written());
+        return CbzWriter::from_zip_output(inner, events, metadata);
+    }
+}
+
+impl<W: Write> CbzWriter<W> {
+    /// Creates a writer for outputs that cannot seek, like pipes or sockets
+    pub fn new_streaming(inner: W, metadata: Metadata) -> Result<CbzWriter<W>, EpubWriterError> {
+        let events = EventDispatcher::new();
+        let inner = ZipOutput::new_streaming(inner, events.bytes_written());
+        return CbzWriter::from_zip_output(inner, events, metadata);
+    }
+
+    fn from_zip_output(
+        inner: ZipOutput<W>,
+        events: EventDispatcher,
+        metadata: Metadata,
+    ) -> Result<CbzWriter<W>, EpubWriterError> {
+        metadata.validate()?;
+
+        return Ok(CbzWriter {
+            metadata,
+            pages: PageSequence::new(),
+            events,
+            finalized: false,
+            inner,
+        });
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        self.events.set_observer(observer);
+    }
+
+    pub fn set_cover<T: std::io::Read>(&mut self, image: &mut T) -> Result<(), EpubWriterError> {
+        return self.set_prepared_cover(PreparedImage::read(image)?);
+    }
+
+    pub fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let img_filename = self.pages.set_cover(page_image)?.image_file_name();
+        self.add_zip_entry(&img_filename, &buffer, zip::CompressionMethod::Stored)?;
+
+        self.events.notify(EpubWriterEvent::CoverSet {
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    pub fn add_image<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_image_with_options(
+            image,
+            PageOptions {
+                nav_label: label,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn add_image_with_options<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(PreparedImage::read(image)?, options);
+    }
+
+    pub fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let page_image = self.pages.add_image(page_image, options)?;
+        let (spread, size) = (page_image.spread, page_image.size);
+        let img_filename = page_image.image_file_name();
+        let index = self.pages.images.len() - 1;
+        if spread {
+            self.events
+                .notify(EpubWriterEvent::SpreadDetected { index, size });
+        }
+
+        self.add_zip_entry(&img_filename, &buffer, zip::CompressionMethod::Stored)?;
+
+        self.events.notify(EpubWriterEvent::PageAdded {
+            index,
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    pub fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Ok(());
+        }
+
+        self.finalized = true;
+        self.pages.validate()?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Started));
+
+        let xml = self.generate_comic_info_xml()?;
+        self.add_zip_entry("ComicInfo.xml", &xml, zip::CompressionMethod::Deflated)?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::MetadataWritten));
+        self.inner.finish()?;
+
+        self.events.notify_bytes_written();
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Completed));
+        return Ok(());
+    }
+
+    fn generate_comic_info_xml(&self) -> xml::writer::Result<Vec<u8>> {
+        fn add_element<W: Write>(
+            writer: &mut EventWriter<W>,
+            name: &str,
+            content: &str,
+        ) -> xml::writer::Result<()> {
+            writer.write(XmlEvent::start_element(name))?;
+            writer.write(XmlEvent::characters(content))?;
+            writer.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+
+        fn add_page<W: Write>(
+            writer: &mut EventWriter<W>,
+            index: usize,
+            page_image: &PageImage,
+            page_type: &str,
+        ) -> xml::writer::Result<()> {
+            let index = index.to_string();
+            let width = page_image.size.0.to_string();
+            let height = page_image.size.1.to_string();
+            let mut xml_event = XmlEvent::start_element("Page")
+                .attr("Image", &index)
+                .attr("Type", page_type)
+                .attr(
+                    "DoublePage",
+                    if page_image.spread { "true" } else { "false" },
+                )
+                .attr("ImageWidth", &width)
+                .attr("ImageHeight", &height);
+            if let Some(d) = page_image.nav_label.as_ref() {
+                xml_event = xml_event.attr("Bookmark", d);
+            }
+
+            writer.write(xml_event)?;
+            writer.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+
+        let mut buffer = Vec::<u8>::new();
+        let mut xml_writer = EventWriter::new_with_config(
+            Cursor::new(&mut buffer),
+            EmitterConfig {
+                perform_indent: true,
+                ..Default::default()
+            },
+        );
+
+        xml_writer.write(
+            XmlEvent::start_element("ComicInfo")
+                .ns("xsi", "http://www.w3.org/2001/XMLSchema-instance")
+                .ns("xsd", "http://www.w3.org/2001/XMLSchema"),
+        )?;
+
+        add_element(&mut xml_writer, "Title", &self.metadata.title)?;
+        if let Some(d) = self.metadata.series.as_ref() {
+            add_element(&mut xml_writer, "Series", d)?;
+        }
+        if let Some(d) = self.metadata.description.as_ref() {
+            add_element(&mut xml_writer, "Summary", d)?;
+        }
+
+        let published_date = self.metadata.published_date;
+        add_element(&mut xml_writer, "Year", &published_date.year().to_string())?;
+        add_element(
+            &mut xml_writer,
+            "Month",
+            &published_date.month().to_string(),
+        )?;
+        add_element(&mut xml_writer, "Day", &published_date.day().to_string())?;
+
+        add_element(&mut xml_writer, "Writer", &self.metadata.author)?;
+        add_element(&mut xml_writer, "Publisher", &self.metadata.publisher)?;
+        if !self.metadata.tags.is_empty() {
+            let tags = self
+                .metadata
+                .tags
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(",");
+            add_element(&mut xml_writer, "Tags", &tags)?;
+        }
+        if let Some(d) = self.metadata.source.as_ref() {
+            add_element(&mut xml_writer, "Web", d)?;
+        }
+
+        add_element(
+            &mut xml_writer,
+            "PageCount",
+            &(self.pages.images.len() + 1).to_string(),
+        )?;
+        add_element(&mut xml_writer, "LanguageISO", &self.metadata.language)?;
+        if self.metadata.right_to_left {
+            add_element(&mut xml_writer, "Manga", "YesAndRightToLeft")?;
+        }
+
+        xml_writer.write(XmlEvent::start_element("Pages"))?;
+        add_page(
+            &mut xml_writer,
+            0,
+            self.pages.cover.as_ref().unwrap(),
+            "FrontCover",
+        )?;
+        for (i, page_image) in self.pages.images.iter().enumerate() {
+            add_page(&mut xml_writer, i + 1, page_image, "Story")?;
+        }
+        xml_writer.write(XmlEvent::end_element())?;
+
+        xml_writer.write(XmlEvent::end_element())?;
+        return Ok(buffer);
+    }
+
+    fn add_zip_entry(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        compression_method: zip::CompressionMethod,
+    ) -> Result<(), EpubWriterError> {
+        let options =
+            zip::write::SimpleFileOptions::default().compression_method(compression_method);
+        self.inner.start_file(name, options)?;
+        self.inner.write_all(data)?;
+        return Ok(());
+    }
+}
+
+impl<W: Write> BookWriter for CbzWriter<W> {
+    fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        CbzWriter::set_observer(self, observer);
+    }
+
+    fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return CbzWriter::set_prepared_cover(self, image);
+    }
+
+    fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return CbzWriter::add_prepared_image(self, image, options);
+    }
+
+    fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        return CbzWriter::finalize(self);
+    }
+}
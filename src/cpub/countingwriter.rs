@@ -0,0 +1,70 @@
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Passes data through to the inner writer, keeping track of the furthest position written to.
+///
+/// The count is shared, so it can be read while the writer is owned by something else.
+/// Seeking is forwarded when created with `new_seekable`, otherwise only seeks that
+/// leave the position unchanged succeed.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    position: u64,
+    bytes_written: Arc<AtomicU64>,
+    seek: Option<fn(&mut W, SeekFrom) -> Result<u64>>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W, bytes_written: Arc<AtomicU64>) -> CountingWriter<W> {
+        CountingWriter {
+            inner,
+            position: 0,
+            bytes_written,
+            seek: None,
+        }
+    }
+
+    pub fn new_seekable(inner: W, bytes_written: Arc<AtomicU64>) -> CountingWriter<W>
+    where
+        W: Seek,
+    {
+        CountingWriter {
+            inner,
+            position: 0,
+            bytes_written,
+            seek: Some(W::seek),
+        }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let output = self.inner.write(buf)?;
+        self.position += output as u64;
+        self.bytes_written
+            .fetch_max(self.position, Ordering::Relaxed);
+        return Ok(output);
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        return self.inner.flush();
+    }
+}
+
+impl<W: Write> Seek for CountingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        match (self.seek, pos) {
+            (Some(seek), _) => self.position = seek(&mut self.inner, pos)?,
+            (None, SeekFrom::Current(0) | SeekFrom::End(0)) => {}
+            (None, SeekFrom::Start(d)) if d == self.position => {}
+            (None, _) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Output does not support seeking",
+                ))
+            }
+        }
+
+        return Ok(self.position);
+    }
+}
@@ -0,0 +1,64 @@
+use super::pagetemplates::PageTemplates;
+
+#[derive(Clone, Default)]
+pub struct EpubWriterOptions {
+    /// Also write EPUB 2 navigation data (`toc.ncx`, guide and cover meta) for older readers
+    pub epub2_compatibility: bool,
+    /// Write `META-INF/com.apple.ibooks.display-options.xml` for Apple Books
+    pub apple_display_options: Option<AppleDisplayOptions>,
+    pub compression_policy: CompressionPolicy,
+    /// Deflate level, from 0 (fastest) to 9 (smallest), the library default when not set
+    pub compression_level: Option<u32>,
+    /// Fail instead of writing ZIP64 records when the book has more than 65535 entries
+    /// or exceeds 4 GiB, for readers not supporting them
+    pub disable_zip64: bool,
+    pub page_templates: PageTemplates,
+}
+
+#[derive(Clone, Default)]
+pub struct AppleDisplayOptions {
+    pub orientation_lock: OrientationLock,
+    /// Show the first page as part of a two page spread instead of on its own
+    pub open_to_spread: bool,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrientationLock {
+    #[default]
+    None,
+    PortraitOnly,
+    LandscapeOnly,
+}
+
+/// Which entries get compressed. Only stored and deflated entries are ever written,
+/// as those are the only methods every reader is required to support.
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompressionPolicy {
+    /// Store images as they are, since deflating them again gains next to nothing,
+    /// and deflate markup and metadata
+    #[default]
+    StoreImages,
+    DeflateAll,
+    /// Store everything, for the fastest generation or tools unable to inflate
+    StoreAll,
+}
+
+impl CompressionPolicy {
+    pub(super) fn compression_method(&self, image: bool) -> zip::CompressionMethod {
+        match (self, image) {
+            (CompressionPolicy::DeflateAll, _) => zip::CompressionMethod::Deflated,
+            (CompressionPolicy::StoreImages, false) => zip::CompressionMethod::Deflated,
+            _ => zip::CompressionMethod::Stored,
+        }
+    }
+}
+
+impl OrientationLock {
+    pub fn display_option_value(&self) -> &'static str {
+        match self {
+            OrientationLock::None => "none",
+            OrientationLock::PortraitOnly => "portrait-only",
+            OrientationLock::LandscapeOnly => "landscape-only",
+        }
+    }
+}
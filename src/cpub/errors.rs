@@ -23,6 +23,9 @@ pub enum EpubWriterError {
     #[error("Cover already set")]
     CoverNotSetError,
 
+    #[error("Back cover already set")]
+    BackCoverAlreadySetError,
+
     #[error("Cover cannot be wider than tall")]
     CoverSizeError,
 
@@ -35,6 +38,21 @@ pub enum EpubWriterError {
     #[error("Spread not allowed at page {page_number}")]
     PageSortingError { page_number: u32 },
 
+    #[error("Book too large to be written without ZIP64 records, which have been disabled")]
+    Zip64RequiredError,
+
+    #[error("Page document {file_number} has no body to convert to AZW3 text")]
+    Azw3PageDocumentError { file_number: usize },
+
+    #[error("Book too large for the AZW3 format, which allows at most 65535 records and 4 GiB")]
+    Azw3SizeError,
+
+    #[error("Invalid {name} page template: {reason}")]
+    PageTemplateError { name: &'static str, reason: String },
+
+    #[error("Unable to append to existing book: {reason}")]
+    ExistingBookError { reason: String },
+
     #[error(transparent)]
     IOError(#[from] std::io::Error),
 
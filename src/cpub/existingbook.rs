@@ -0,0 +1,440 @@
+use super::errors::EpubWriterError;
+use super::metadata::Metadata;
+use super::pageimage::PageImage;
+use super::pagesequence::{PageSequence, BACK_COVER_BASE_NAME, COVER_BASE_NAME};
+use chrono::{NaiveDate, TimeZone, Utc};
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+use xml::attribute::OwnedAttribute;
+use xml::reader::{EventReader, XmlEvent};
+use zip::ZipArchive;
+
+const CONTENT_OPF_PATH: &str = "OEBPS/content.opf";
+const NAV_PATH: &str = "OEBPS/nav.xhtml";
+/// Entries written again on finalizing, along with the cover spacer
+const REGENERATED_PATHS: [&str; 3] = [CONTENT_OPF_PATH, NAV_PATH, "OEBPS/toc.ncx"];
+
+/// Book previously written by `EpubWriter`, read back so that pages can be appended to it.
+///
+/// Pages are restored from the package document and navigation. Their documents and images
+/// are not generated again, but copied over from the existing book.
+pub struct ExistingBook {
+    pub metadata: Metadata,
+    pub pages: PageSequence,
+    /// Base names of images in the book, by content hash
+    pub image_names: HashMap<[u8; 32], String>,
+}
+
+impl ExistingBook {
+    pub fn read<R: Read + Seek>(
+        archive: &mut ZipArchive<R>,
+    ) -> Result<ExistingBook, EpubWriterError> {
+        let package = read_package(archive)?;
+        let navigation = read_navigation(archive)?;
+        let rtl = package.metadata.right_to_left;
+
+        let mut images = HashMap::<String, PageImage>::new();
+        let mut image_names = HashMap::new();
+        for i in package.manifest.iter() {
+            if !i.media_type.starts_with("image/") {
+                continue;
+            }
+
+            let data = read_entry(archive, &format!("OEBPS/{}", i.href))?;
+            let mut page_image = PageImage::new(&data, None)?;
+            page_image.base_name = strip_extension(&i.href).to_string();
+            image_names.insert(page_image.content_hash, page_image.base_name.clone());
+            images.insert(page_image.base_name.clone(), page_image);
+        }
+
+        // Halves of spreads share the base name of their image
+        let mut documents_by_base_name = Vec::<(String, Vec<String>)>::new();
+        for i in package.spine.iter() {
+            let stem = strip_extension(i);
+            let base_name = stem
+                .strip_suffix("_L")
+                .or_else(|| stem.strip_suffix("_R"))
+                .unwrap_or(stem);
+            match documents_by_base_name.last_mut() {
+                Some((d, documents)) if d == base_name => documents.push(i.clone()),
+                _ => documents_by_base_name.push((base_name.to_string(), vec![i.clone()])),
+            }
+        }
+
+        let spacer_base_name = format!("{}_Spacer", COVER_BASE_NAME);
+        let mut pages = PageSequence::new();
+        for (base_name, documents) in documents_by_base_name.iter() {
+            if *base_name == spacer_base_name {
+                pages.add_existing_cover_spacer();
+                continue;
+            }
+
+            let page_image = restore_page_image(archive, &package, &images, base_name, documents)?;
+            if base_name == COVER_BASE_NAME {
+                pages.set_cover(page_image)?;
+            } else if base_name == BACK_COVER_BASE_NAME {
+                pages.set_back_cover(page_image)?;
+            } else {
+                let mut page_image = page_image;
+                let first_document = page_image.page_file_names(rtl).remove(0);
+                page_image.nav_label = navigation.toc.get(&first_document).cloned();
+                page_image.page_number = navigation.page_list.get(&first_document).cloned();
+                page_image.back_matter = navigation
+                    .landmarks
+                    .iter()
+                    .any(|(epub_type, href)| epub_type == "backmatter" && *href == first_document);
+                pages.add_existing_image(page_image)?;
+            }
+        }
+
+        if pages.cover.is_none() || pages.images.is_empty() {
+            return Err(not_written_by_cpub_error());
+        }
+
+        return Ok(ExistingBook {
+            metadata: package.metadata,
+            pages,
+            image_names,
+        });
+    }
+
+    /// Whether an entry of the existing book is written again on finalizing, rather than copied
+    pub fn is_regenerated(name: &str) -> bool {
+        return REGENERATED_PATHS.contains(&name)
+            || name == format!("OEBPS/{}_Spacer.xhtml", COVER_BASE_NAME);
+    }
+}
+
+/// Reads only the metadata of a book previously written by `EpubWriter`
+pub fn read_metadata<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Metadata, EpubWriterError> {
+    return Ok(read_package(archive)?.metadata);
+}
+
+struct ManifestItem {
+    href: String,
+    media_type: String,
+    properties: String,
+}
+
+/// Contents of the package document pages are restored from
+struct Package {
+    metadata: Metadata,
+    manifest: Vec<ManifestItem>,
+    /// Hrefs of documents in reading order
+    spine: Vec<String>,
+}
+
+/// Entries of the navigation document, by page document href
+struct Navigation {
+    toc: HashMap<String, String>,
+    page_list: HashMap<String, String>,
+    /// Landmark types and hrefs
+    landmarks: Vec<(String, String)>,
+}
+
+/// Details of a page document needed when they cannot be told from its image
+struct PageDocument {
+    viewport: Option<(u32, u32)>,
+    image_href: Option<String>,
+}
+
+fn read_package<R: Read + Seek>(archive: &mut ZipArchive<R>) -> Result<Package, EpubWriterError> {
+    let data = read_entry(archive, CONTENT_OPF_PATH).map_err(|_| not_written_by_cpub_error())?;
+
+    let mut metadata = Metadata::default();
+    let mut manifest_ids = Vec::<(String, ManifestItem)>::new();
+    let mut spine_ids = Vec::<String>::new();
+    let mut property = None;
+    let mut text = String::new();
+    for event in EventReader::new(data.as_slice()) {
+        match event.map_err(|d| xml_error(CONTENT_OPF_PATH, d))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                match name.local_name.as_str() {
+                    "item" => manifest_ids.push((
+                        attribute(&attributes, "id").unwrap_or_default(),
+                        ManifestItem {
+                            href: attribute(&attributes, "href").unwrap_or_default(),
+                            media_type: attribute(&attributes, "media-type").unwrap_or_default(),
+                            properties: attribute(&attributes, "properties").unwrap_or_default(),
+                        },
+                    )),
+                    "itemref" => spine_ids.extend(attribute(&attributes, "idref")),
+                    "spine" => {
+                        metadata.right_to_left =
+                            attribute(&attributes, "page-progression-direction").as_deref()
+                                == Some("rtl")
+                    }
+                    _ => {}
+                }
+                property = attribute(&attributes, "property");
+                text.clear();
+            }
+            XmlEvent::Characters(d) | XmlEvent::CData(d) => text.push_str(&d),
+            XmlEvent::EndElement { name } => {
+                let text = text.clone();
+                match (name.prefix.as_deref(), name.local_name.as_str()) {
+                    (Some("dc"), "identifier") => metadata.id = text,
+                    (Some("dc"), "title") => metadata.title = text,
+                    (Some("dc"), "creator") => metadata.author = text,
+                    (Some("dc"), "publisher") => metadata.publisher = text,
+                    (Some("dc"), "date") => {
+                        if let Ok(d) = NaiveDate::parse_from_str(&text, "%Y-%m-%d") {
+                            metadata.published_date =
+                                Utc.from_utc_datetime(&d.and_hms_opt(0, 0, 0).unwrap());
+                        }
+                    }
+                    (Some("dc"), "language") => metadata.language = text,
+                    (Some("dc"), "description") => metadata.description = Some(text),
+                    (Some("dc"), "subject") => {
+                        match property.as_deref().and_then(|d| d.strip_prefix("cpublib:")) {
+                            Some(key) => {
+                                metadata.custom.insert(key.to_string(), text);
+                            }
+                            None => {
+                                metadata.tags.insert(text);
+                            }
+                        }
+                    }
+                    (Some("dc"), "source") => metadata.source = Some(text),
+                    (Some("dc"), "relation") => metadata.relation = Some(text),
+                    (Some("dc"), "rights") => metadata.copyright = Some(text),
+                    (_, "meta") => match property.as_deref() {
+                        Some("belongs-to-collection") => metadata.series = Some(text),
+                        Some("schema:accessMode") => {
+                            metadata.access_modes.insert(text);
+                        }
+                        Some("schema:accessModeSufficient") => {
+                            metadata.access_modes_sufficient.insert(text);
+                        }
+                        Some("schema:accessibilityFeature") => {
+                            metadata.accessibility_features.insert(text);
+                        }
+                        Some("schema:accessibilityHazard") => {
+                            metadata.accessibility_hazards.insert(text);
+                        }
+                        Some("schema:accessibilitySummary") => {
+                            metadata.accessibility_summary = Some(text)
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+                property = None;
+            }
+            _ => {}
+        }
+    }
+
+    let spine = spine_ids
+        .iter()
+        .filter_map(|id| manifest_ids.iter().find(|(d, _)| d == id))
+        .map(|(_, item)| item.href.clone())
+        .collect();
+    return Ok(Package {
+        metadata,
+        manifest: manifest_ids.into_iter().map(|(_, item)| item).collect(),
+        spine,
+    });
+}
+
+fn read_navigation<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+) -> Result<Navigation, EpubWriterError> {
+    let data = read_entry(archive, NAV_PATH).map_err(|_| not_written_by_cpub_error())?;
+
+    let mut output = Navigation {
+        toc: HashMap::new(),
+        page_list: HashMap::new(),
+        landmarks: Vec::new(),
+    };
+    let mut nav_type = String::new();
+    let mut link = None;
+    let mut text = String::new();
+    for event in EventReader::new(data.as_slice()) {
+        match event.map_err(|d| xml_error(NAV_PATH, d))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => match name.local_name.as_str() {
+                "nav" => nav_type = attribute(&attributes, "type").unwrap_or_default(),
+                "a" => {
+                    link = Some((
+                        attribute(&attributes, "href").unwrap_or_default(),
+                        attribute(&attributes, "type").unwrap_or_default(),
+                    ));
+                    text.clear();
+                }
+                _ => {}
+            },
+            XmlEvent::Characters(d) | XmlEvent::CData(d) => text.push_str(&d),
+            XmlEvent::EndElement { name } if name.local_name == "a" => {
+                let (href, epub_type) = match link.take() {
+                    Some(d) => d,
+                    None => continue,
+                };
+                match nav_type.as_str() {
+                    "toc" => {
+                        output.toc.entry(href).or_insert(text.clone());
+                    }
+                    "page-list" => {
+                        output.page_list.entry(href).or_insert(text.clone());
+                    }
+                    "landmarks" => output.landmarks.push((epub_type, href)),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    return Ok(output);
+}
+
+/// Page image with the base name, layout and image file of a page in the existing book.
+/// Pages referencing the image of an earlier one are restored as its duplicates.
+fn restore_page_image<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    package: &Package,
+    images: &HashMap<String, PageImage>,
+    base_name: &str,
+    documents: &[String],
+) -> Result<PageImage, EpubWriterError> {
+    let spread = documents.len() > 1;
+    let svg = package
+        .manifest
+        .iter()
+        .find(|d| d.href == documents[0])
+        .is_some_and(|d| d.properties.split_whitespace().any(|d| d == "svg"));
+    if let (true, Some(d)) = (svg, images.get(base_name)) {
+        let mut output = d.clone();
+        output.spread = spread;
+        return Ok(output);
+    }
+
+    let document = read_page_document(archive, &documents[0])?;
+    let image = images.get(base_name).or_else(|| {
+        let href = document.image_href.as_deref()?;
+        return images.get(strip_extension(href));
+    });
+    let viewport = document.viewport.map(|(width, height)| match spread {
+        true => (width * 2, height),
+        false => (width, height),
+    });
+
+    let mut output = match (image, viewport) {
+        (Some(d), _) => {
+            let mut output = d.clone();
+            if d.base_name != base_name {
+                output.duplicate_of = Some(d.base_name.clone());
+            }
+            output
+        }
+        (None, Some(d)) => PageImage::new_text_page(d, String::new()),
+        (None, None) => {
+            return Err(EpubWriterError::ExistingBookError {
+                reason: format!("unable to tell the size of page {}", documents[0]),
+            })
+        }
+    };
+    output.base_name = base_name.to_string();
+    output.spread = spread;
+    if !svg {
+        // Text page documents are copied over, the body only tells them apart from image pages
+        output.size = viewport.unwrap_or(output.size);
+        output.text_body = Some(String::new());
+    } else {
+        output.text_body = None;
+    }
+
+    return Ok(output);
+}
+
+fn read_page_document<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    href: &str,
+) -> Result<PageDocument, EpubWriterError> {
+    let path = format!("OEBPS/{}", href);
+    let data = read_entry(archive, &path)?;
+
+    let mut output = PageDocument {
+        viewport: None,
+        image_href: None,
+    };
+    for event in EventReader::new(data.as_slice()) {
+        let (name, attributes) = match event.map_err(|d| xml_error(&path, d))? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => (name, attributes),
+            _ => continue,
+        };
+
+        match name.local_name.as_str() {
+            "meta" if attribute(&attributes, "name").as_deref() == Some("viewport") => {
+                output.viewport =
+                    attribute(&attributes, "content").and_then(|d| parse_viewport(&d));
+            }
+            "image" if output.image_href.is_none() => {
+                output.image_href = attribute(&attributes, "href");
+            }
+            "img" if output.image_href.is_none() => {
+                output.image_href = attribute(&attributes, "src");
+            }
+            _ => {}
+        }
+    }
+
+    return Ok(output);
+}
+
+/// Width and height of a viewport meta tag content, as in `width=800, height=1200`
+fn parse_viewport(content: &str) -> Option<(u32, u32)> {
+    let (mut width, mut height) = (None, None);
+    for i in content.split(',') {
+        match i
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+        {
+            Some(("width", value)) => width = value.parse().ok(),
+            Some(("height", value)) => height = value.parse().ok(),
+            _ => {}
+        }
+    }
+
+    return Some((width?, height?));
+}
+
+/// Value of an attribute by local name, whatever its namespace
+fn attribute(attributes: &[OwnedAttribute], name: &str) -> Option<String> {
+    return attributes
+        .iter()
+        .find(|d| d.name.local_name == name)
+        .map(|d| d.value.clone());
+}
+
+fn read_entry<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+) -> Result<Vec<u8>, EpubWriterError> {
+    let mut output = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut output)?;
+    return Ok(output);
+}
+
+fn strip_extension(href: &str) -> &str {
+    return href.rsplit_once('.').map_or(href, |(stem, _)| stem);
+}
+
+fn xml_error(name: &str, error: xml::reader::Error) -> EpubWriterError {
+    return EpubWriterError::ExistingBookError {
+        reason: format!("{}: {}", name, error),
+    };
+}
+
+fn not_written_by_cpub_error() -> EpubWriterError {
+    return EpubWriterError::ExistingBookError {
+        reason: "not a book written by cpub".to_string(),
+    };
+}
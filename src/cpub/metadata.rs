@@ -1,8 +1,10 @@
 use chrono::prelude::*;
 use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Read, Seek};
 use uuid::Uuid;
 
-use super::errors::MetadataValidationError;
+use super::errors::{EpubWriterError, MetadataValidationError};
+use super::existingbook;
 
 const DEFAULT_LANGUAGE: &str = "en-us";
 
@@ -22,9 +24,24 @@ pub struct Metadata {
     pub tags: BTreeSet<String>,
     pub custom: BTreeMap<String, String>,
     pub right_to_left: bool,
+    /// Schema.org access modes needed to consume the book, e.g. `visual`, see EPUB Accessibility 1.1
+    pub access_modes: BTreeSet<String>,
+    /// Comma separated sets of access modes each sufficient to consume the book, e.g. `visual` or `textual,visual`
+    pub access_modes_sufficient: BTreeSet<String>,
+    /// Accessibility features present, e.g. `alternativeText` or `printPageNumbers`
+    pub accessibility_features: BTreeSet<String>,
+    /// Hazards present, e.g. `flashing`, or `none`
+    pub accessibility_hazards: BTreeSet<String>,
+    pub accessibility_summary: Option<String>,
 }
 
 impl Metadata {
+    /// Reads metadata of a book previously written by `EpubWriter`, to keep it when appending pages
+    pub fn from_epub<R: Read + Seek>(existing: R) -> Result<Metadata, EpubWriterError> {
+        let mut archive = zip::ZipArchive::new(existing)?;
+        return existingbook::read_metadata(&mut archive);
+    }
+
     pub fn validate(&self) -> Result<(), MetadataValidationError> {
         fn is_invalid_string(value: &str) -> bool {
             value.is_empty() || value.split_whitespace().count() == 0
@@ -67,6 +84,11 @@ impl Default for Metadata {
             tags: BTreeSet::default(),
             custom: BTreeMap::default(),
             right_to_left: false,
+            access_modes: BTreeSet::default(),
+            access_modes_sufficient: BTreeSet::default(),
+            accessibility_features: BTreeSet::default(),
+            accessibility_hazards: BTreeSet::default(),
+            accessibility_summary: None,
         }
     }
 }
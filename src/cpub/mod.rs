@@ -1,79 +1,243 @@
+#[cfg(feature = "async")]
+mod asyncepubwriter;
+mod azw3writer;
+mod bookwriter;
+mod cbzwriter;
+mod countingwriter;
+mod epubwriteroptions;
 mod errors;
+mod existingbook;
 mod metadata;
+mod observer;
 mod pageimage;
+mod pageoptions;
+mod pagesequence;
+mod pagetemplates;
+mod pdfwriter;
+mod preparedimage;
 mod templates;
+mod textpage;
+#[cfg(feature = "async")]
+mod writelog;
+mod zipoutput;
 
+use self::errors::EpubWriterError;
+use self::existingbook::ExistingBook;
+use self::observer::EventDispatcher;
+use self::pageimage::PageImage;
+use self::zipoutput::ZipOutput;
+
+/// Sizes and offsets at or above this need ZIP64 records
+const ZIP64_BYTES_LIMIT: u64 = u32::MAX as u64;
+/// Archives with more entries than this need ZIP64 records
+const ZIP64_ENTRIES_LIMIT: usize = u16::MAX as usize;
+#[cfg(feature = "async")]
+pub use asyncepubwriter::AsyncEpubWriter;
+pub use azw3writer::Azw3Writer;
+pub use bookwriter::BookWriter;
+pub use cbzwriter::CbzWriter;
+pub use epubwriteroptions::{
+    AppleDisplayOptions, CompressionPolicy, EpubWriterOptions, OrientationLock,
+};
 pub use metadata::Metadata;
-use pageimage::PageImage;
+pub use observer::{EpubWriterEvent, EpubWriterObserver, FinalizePhase};
+pub use pageoptions::PageOptions;
+use pagesequence::PageSequence;
+pub use pagetemplates::PageTemplates;
+pub use pdfwriter::PdfWriter;
+pub use preparedimage::{ImageProbe, PreparedImage};
+use std::collections::HashMap;
 use std::io::{prelude::*, Cursor};
+use std::sync::atomic::Ordering;
 use std::vec::Vec;
+pub use textpage::TextPage;
 use xml::writer::XmlEvent;
 use xml::{EmitterConfig, EventWriter};
-use zip::ZipWriter;
-
-use self::errors::EpubWriterError;
 
-pub struct EpubWriter<W: Write + Seek> {
+pub struct EpubWriter<W: Write> {
     metadata: Metadata,
-    images: Vec<PageImage>,
-    cover: Option<PageImage>,
-    cover_spacer_required: bool,
-    spread_added: bool,
+    options: EpubWriterOptions,
+    pages: PageSequence,
+    events: EventDispatcher,
     finalized: bool,
-    current_chapter_number: u32,
-    current_page_number: u32,
-    total_pages_number: u32,
-    inner: ZipWriter<W>,
+    zip_entries: usize,
+    /// Base names of images written so far, by content hash
+    image_names: HashMap<[u8; 32], String>,
+    inner: ZipOutput<W>,
 }
 
 impl<W: Write + Seek> EpubWriter<W> {
     pub fn new(inner: W, metadata: Metadata) -> Result<EpubWriter<W>, EpubWriterError> {
-        metadata.validate()?;
+        return EpubWriter::new_with_options(inner, metadata, EpubWriterOptions::default());
+    }
 
-        let mut output = EpubWriter {
-            metadata: metadata,
-            images: Vec::default(),
-            cover: None,
-            cover_spacer_required: false,
-            spread_added: false,
-            finalized: false,
-            current_chapter_number: 0,
-            current_page_number: 0,
-            total_pages_number: 0,
-            inner: zip::ZipWriter::new(inner),
-        };
+    pub fn new_with_options(
+        inner: W,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<EpubWriter<W>, EpubWriterError> {
+        let events = EventDispatcher::new();
+        let inner = ZipOutput::new_seekable(inner, events.bytes_written());
+        return EpubWriter::from_zip_output(inner, events, metadata, options);
+    }
 
+    /// Creates a writer adding pages to a book previously written by `EpubWriter`, the result
+    /// being written to `inner`. Chapter and page numbering continue after the last page of
+    /// the existing book, whose entries are copied over as they are, while the package document
+    /// and navigation are written again on finalizing to include new pages.
+    ///
+    /// `metadata` replaces that of the existing book, which `Metadata::from_epub` reads
+    /// so that it can be kept. The reading direction cannot change.
+    pub fn new_appending<R: Read + Seek>(
+        existing: R,
+        inner: W,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<EpubWriter<W>, EpubWriterError> {
+        let mut archive = zip::ZipArchive::new(existing)?;
+        let book = ExistingBook::read(&mut archive)?;
+        if book.metadata.right_to_left != metadata.right_to_left {
+            return Err(EpubWriterError::ExistingBookError {
+                reason: "reading direction cannot change".to_string(),
+            });
+        }
+
+        let events = EventDispatcher::new();
+        let inner = ZipOutput::new_seekable(inner, events.bytes_written());
+        let mut output = EpubWriter::new_empty(inner, events, metadata, options)?;
+        output.pages = book.pages;
+        output.image_names = book.image_names;
+
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i)?;
+            let name = file.name()?.to_string();
+            if !ExistingBook::is_regenerated(&name) {
+                output.check_zip64_limits(1, max_entry_size(&name, file.compressed_size()))?;
+                output.inner.raw_copy_file(file)?;
+                output.zip_entries += 1;
+            }
+        }
+
+        output.events.notify_bytes_written();
+        return Ok(output);
+    }
+}
+
+impl<W: Write> EpubWriter<W> {
+    /// Creates a writer for outputs that cannot seek, like pipes or sockets.
+    ///
+    /// Entry sizes and checksums, except those of `mimetype`, are written after entry data,
+    /// which some older readers may not support: prefer `new` when writing to files.
+    pub fn new_streaming(
+        inner: W,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<EpubWriter<W>, EpubWriterError> {
+        let events = EventDispatcher::new();
+        let inner = ZipOutput::new_streaming(inner, events.bytes_written());
+        return EpubWriter::from_zip_output(inner, events, metadata, options);
+    }
+
+    fn from_zip_output(
+        inner: ZipOutput<W>,
+        events: EventDispatcher,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<EpubWriter<W>, EpubWriterError> {
+        let mut output = EpubWriter::new_empty(inner, events, metadata, options)?;
         output.add_static_data()?;
         return Ok(output);
     }
 
+    /// Writer with no entries written yet
+    fn new_empty(
+        inner: ZipOutput<W>,
+        events: EventDispatcher,
+        metadata: Metadata,
+        options: EpubWriterOptions,
+    ) -> Result<EpubWriter<W>, EpubWriterError> {
+        metadata.validate()?;
+        options.page_templates.validate()?;
+
+        return Ok(EpubWriter {
+            metadata,
+            options,
+            pages: PageSequence::new(),
+            events,
+            finalized: false,
+            zip_entries: 0,
+            image_names: HashMap::new(),
+            inner,
+        });
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        self.events.set_observer(observer);
+    }
+
     pub fn set_cover<T: std::io::Read>(&mut self, image: &mut T) -> Result<(), EpubWriterError> {
+        return self.set_prepared_cover(PreparedImage::read(image)?);
+    }
+
+    pub fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
         if self.finalized {
             return Err(EpubWriterError::FinalizedError());
         }
 
-        if self.cover.is_some() {
-            return Err(EpubWriterError::CoverAlreadySetError);
+        let (buffer, page_image) = image.into_parts();
+        let page_image = self.pages.set_cover(page_image)?;
+        let img_filename = page_image.image_file_name();
+        let pages = page_image.generate_pages_xml(&self.metadata, &self.options.page_templates);
+        self.image_names
+            .insert(page_image.content_hash, page_image.base_name.clone());
+
+        self.add_image_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        for (page_name, page_content) in pages.iter() {
+            self.add_zip_entry(&format!("OEBPS/{}", page_name), page_content.as_bytes())?;
         }
 
-        let mut buffer: Vec<u8> = Vec::new();
-        image.read_to_end(&mut buffer)?;
-        let mut page_image = PageImage::new(&buffer, None)?;
-        if page_image.spread {
-            return Err(EpubWriterError::CoverSizeError);
+        self.events.notify(EpubWriterEvent::CoverSet {
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    pub fn set_back_cover<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+    ) -> Result<(), EpubWriterError> {
+        return self.set_prepared_back_cover(PreparedImage::read(image)?);
+    }
+
+    /// Sets the image shown after all pages, on the side of the last spread
+    /// closing the book: left when reading left to right, right otherwise.
+    /// Can be set at any point before finalizing.
+    pub fn set_prepared_back_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
         }
 
-        page_image.base_name = "S00-Cover".to_string();
-        self.cover = Some(page_image);
-        let page_image = self.cover.as_ref().unwrap();
+        let (buffer, mut page_image) = image.into_parts();
+        page_image.duplicate_of = self.image_names.get(&page_image.content_hash).cloned();
+        let page_image = self.pages.set_back_cover(page_image)?;
+        let duplicate = page_image.duplicate_of.is_some();
+        let (content_hash, base_name) = (page_image.content_hash, page_image.base_name.clone());
         let img_filename = page_image.image_file_name();
-        let pages = page_image.generate_pages_xml(self.metadata.right_to_left);
+        let pages = page_image.generate_pages_xml(&self.metadata, &self.options.page_templates);
 
-        self.add_zip_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        if !duplicate {
+            self.image_names.insert(content_hash, base_name);
+            self.add_image_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        }
         for (page_name, page_content) in pages.iter() {
             self.add_zip_entry(&format!("OEBPS/{}", page_name), page_content.as_bytes())?;
         }
 
+        self.events.notify(EpubWriterEvent::BackCoverSet {
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
         return Ok(());
     }
 
@@ -81,52 +245,136 @@ impl<W: Write + Seek> EpubWriter<W> {
         &mut self,
         image: &mut T,
         label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_image_with_options(
+            image,
+            PageOptions {
+                nav_label: label,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn add_image_with_options<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(PreparedImage::read(image)?, options);
+    }
+
+    pub fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
     ) -> Result<(), EpubWriterError> {
         if self.finalized {
             return Err(EpubWriterError::FinalizedError());
         }
 
-        let mut buffer: Vec<u8> = Vec::new();
-        image.read_to_end(&mut buffer)?;
-        let mut page_image = PageImage::new(&buffer, label)?;
-        if page_image.spread {
-            if self.total_pages_number % 2 == 0 {
-                self.total_pages_number += 2;
-            } else {
-                if self.spread_added == false {
-                    self.cover_spacer_required = true;
-                    self.total_pages_number += 1;
-                } else {
-                    return Err(errors::EpubWriterError::PageSortingError {
-                        page_number: (self.images.len() + 1) as u32,
-                    });
-                }
-            }
-            self.spread_added = true;
-        } else {
-            self.total_pages_number += 1;
+        // Identical images are stored once, with page documents referencing the first one added
+        let (buffer, mut page_image) = image.into_parts();
+        page_image.duplicate_of = self.image_names.get(&page_image.content_hash).cloned();
+
+        // Checked before writing anything, so the page is either fully written or not at all
+        let (image_entries, image_size) = match page_image.duplicate_of {
+            Some(_) => (0, 0),
+            None => (
+                1,
+                max_entry_size(&page_image.image_file_name(), buffer.len() as u64),
+            ),
+        };
+        let page_entries = if page_image.spread { 2 } else { 1 };
+        self.check_zip64_limits(image_entries + page_entries, image_size)?;
+
+        let page_image = self.pages.add_image(page_image, options)?;
+        let (spread, size) = (page_image.spread, page_image.size);
+        let duplicate = page_image.duplicate_of.is_some();
+        let (content_hash, base_name) = (page_image.content_hash, page_image.base_name.clone());
+        let img_filename = page_image.image_file_name();
+        let pages = page_image.generate_pages_xml(&self.metadata, &self.options.page_templates);
+        let index = self.pages.images.len() - 1;
+        if spread {
+            self.events
+                .notify(EpubWriterEvent::SpreadDetected { index, size });
         }
 
-        if page_image.nav_label.is_some() || self.current_page_number == 0 {
-            self.current_page_number += 1;
-            self.current_chapter_number = 0;
+        if !duplicate {
+            self.image_names.insert(content_hash, base_name);
+            self.add_image_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        }
+        for (page_name, page_content) in pages.iter() {
+            self.add_zip_entry(&format!("OEBPS/{}", page_name), page_content.as_bytes())?;
         }
-        self.current_chapter_number += 1;
 
-        page_image.base_name = format!(
-            "S01-C{:06}P{:06}",
-            self.current_page_number, self.current_chapter_number
-        );
-        self.images.push(page_image);
-        let page_image = self.images.last().unwrap();
+        self.events.notify(EpubWriterEvent::PageAdded {
+            index,
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    /// Adds a page of text, sized like the page before it
+    pub fn add_text_page(
+        &mut self,
+        page: TextPage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let previous_page = match self.pages.images.last() {
+            Some(d) => d,
+            None => match self.pages.cover.as_ref() {
+                Some(d) => d,
+                None => return Err(EpubWriterError::CoverNotSetError),
+            },
+        };
+        let viewport_size = previous_page.viewport_size();
+
+        let text_body = page.render_body();
+        let (buffer, page_image) = match page.image {
+            Some(image) => {
+                let (buffer, mut page_image) = image.into_parts();
+                page_image.duplicate_of = self.image_names.get(&page_image.content_hash).cloned();
+                page_image.size = viewport_size;
+                page_image.spread = false;
+                page_image.text_body = Some(text_body);
+                (Some(buffer), page_image)
+            }
+            None => (None, PageImage::new_text_page(viewport_size, text_body)),
+        };
+
+        let (image_entries, image_size) = match (&buffer, &page_image.duplicate_of) {
+            (Some(d), None) => (
+                1,
+                max_entry_size(&page_image.image_file_name(), d.len() as u64),
+            ),
+            _ => (0, 0),
+        };
+        self.check_zip64_limits(image_entries + 1, image_size)?;
+
+        let page_image = self.pages.add_image(page_image, options)?;
+        let (content_hash, base_name) = (page_image.content_hash, page_image.base_name.clone());
         let img_filename = page_image.image_file_name();
-        let pages = page_image.generate_pages_xml(self.metadata.right_to_left);
+        let pages = page_image.generate_pages_xml(&self.metadata, &self.options.page_templates);
+        let index = self.pages.images.len() - 1;
 
-        self.add_zip_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        if let (Some(buffer), None) = (buffer, self.image_names.get(&content_hash)) {
+            self.image_names.insert(content_hash, base_name);
+            self.add_image_entry(&format!("OEBPS/{}", &img_filename), &buffer)?;
+        }
         for (page_name, page_content) in pages.iter() {
             self.add_zip_entry(&format!("OEBPS/{}", page_name), page_content.as_bytes())?;
         }
 
+        self.events.notify(EpubWriterEvent::PageAdded {
+            index,
+            file_name: &pages[0].0,
+        });
+        self.events.notify_bytes_written();
         return Ok(());
     }
 
@@ -136,49 +384,67 @@ impl<W: Write + Seek> EpubWriter<W> {
         }
 
         self.finalized = true;
-
-        if self.cover_spacer_required {
-            let (spacer_name, spacer_content) = self.cover.as_ref().unwrap().generate_spacer_page();
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Started));
+
+        if self.pages.cover_spacer_required {
+            let (spacer_name, spacer_content) = self
+                .pages
+                .cover
+                .as_ref()
+                .unwrap()
+                .generate_spacer_page(&self.metadata, &self.options.page_templates);
             self.add_zip_entry(&format!("OEBPS/{}", spacer_name), spacer_content.as_bytes())?;
+            self.events.notify(EpubWriterEvent::SpacerInserted);
         }
         self.add_dynamic_data()?;
         self.inner.finish()?;
 
+        self.events.notify_bytes_written();
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Completed));
         return Ok(());
     }
 
     fn add_static_data(&mut self) -> Result<(), EpubWriterError> {
-        let options = zip::write::FileOptions::default();
-
-        self.inner.start_file(
-            "mimetype",
-            options.compression_method(zip::CompressionMethod::Stored),
-        )?;
-        write!(self.inner, "application/epub+zip")?;
+        // Readers identify ePubs from the start of the file, so even streamed books need
+        // the mimetype size and checksum before its data
+        self.inner
+            .add_stored_file("mimetype", b"application/epub+zip")?;
+        self.zip_entries += 1;
 
         self.add_zip_entry(
             "META-INF/container.xml",
             templates::CONTAINER_XML.as_bytes(),
         )?;
 
+        if let Some(d) = self.options.apple_display_options.as_ref() {
+            let xml = generate_apple_display_options_xml(d)?;
+            self.add_zip_entry("META-INF/com.apple.ibooks.display-options.xml", &xml)?;
+        }
+
         return Ok(());
     }
 
     fn add_dynamic_data(&mut self) -> Result<(), EpubWriterError> {
-        if self.cover.is_none() {
-            return Err(EpubWriterError::CoverNotSetError);
-        }
-
-        if self.images.is_empty() {
-            return Err(EpubWriterError::NoPagesError);
-        }
+        self.pages.validate()?;
 
         let xml = self.generate_content_opf()?;
         self.add_zip_entry("OEBPS/content.opf", &xml)?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::MetadataWritten));
 
         let xml = self.generate_nav_xml()?;
         self.add_zip_entry("OEBPS/nav.xhtml", &xml)?;
 
+        if self.options.epub2_compatibility {
+            let xml = self.generate_toc_ncx()?;
+            self.add_zip_entry("OEBPS/toc.ncx", &xml)?;
+        }
+        self.events.notify(EpubWriterEvent::Finalizing(
+            FinalizePhase::NavigationWritten,
+        ));
+
         return Ok(());
     }
 
@@ -227,18 +493,18 @@ impl<W: Write + Seek> EpubWriter<W> {
         fn manifest_add_page<W: Write>(
             xml_writer: &mut EventWriter<W>,
             file_name: &str,
+            svg: bool,
         ) -> xml::writer::Result<()> {
-            return add_element(
-                xml_writer,
-                "item",
-                None,
-                Some(vec![
-                    ("href", file_name),
-                    ("id", file_name),
-                    ("media-type", "application/xhtml+xml"),
-                    ("properties", "svg"),
-                ]),
-            );
+            let mut attributes = vec![
+                ("href", file_name),
+                ("id", file_name),
+                ("media-type", "application/xhtml+xml"),
+            ];
+            if svg {
+                attributes.push(("properties", "svg"));
+            }
+
+            return add_element(xml_writer, "item", None, Some(attributes));
         }
 
         let mut buffer = Vec::<u8>::new();
@@ -348,7 +614,7 @@ impl<W: Write + Seek> EpubWriter<W> {
                 &mut xml_writer,
                 "meta",
                 Some(d),
-                Some(vec![("property", "belongs-to-collection"), ("id", "c01")])
+                Some(vec![("property", "belongs-to-collection"), ("id", "c01")]),
             )?;
             add_element(
                 &mut xml_writer,
@@ -358,6 +624,52 @@ impl<W: Write + Seek> EpubWriter<W> {
             )?;
         }
 
+        for (property, values) in [
+            ("schema:accessMode", &self.metadata.access_modes),
+            (
+                "schema:accessModeSufficient",
+                &self.metadata.access_modes_sufficient,
+            ),
+            (
+                "schema:accessibilityFeature",
+                &self.metadata.accessibility_features,
+            ),
+            (
+                "schema:accessibilityHazard",
+                &self.metadata.accessibility_hazards,
+            ),
+        ] {
+            for i in values.iter() {
+                add_element(
+                    &mut xml_writer,
+                    "meta",
+                    Some(i),
+                    Some(vec![("property", property)]),
+                )?;
+            }
+        }
+        if let Some(d) = self.metadata.accessibility_summary.as_ref() {
+            add_element(
+                &mut xml_writer,
+                "meta",
+                Some(d),
+                Some(vec![("property", "schema:accessibilitySummary")]),
+            )?;
+        }
+
+        let cover = self.pages.cover.as_ref().unwrap();
+        if self.options.epub2_compatibility {
+            add_element(
+                &mut xml_writer,
+                "meta",
+                None,
+                Some(vec![
+                    ("name", "cover"),
+                    ("content", cover.image_file_name().as_str()),
+                ]),
+            )?;
+        }
+
         xml_writer.write(XmlEvent::end_element())?;
 
         xml_writer.write(XmlEvent::start_element("manifest"))?;
@@ -373,76 +685,159 @@ impl<W: Write + Seek> EpubWriter<W> {
                 ("properties", "nav"),
             ]),
         )?;
+        if self.options.epub2_compatibility {
+            add_element(
+                &mut xml_writer,
+                "item",
+                None,
+                Some(vec![
+                    ("href", "toc.ncx"),
+                    ("id", "ncx"),
+                    ("media-type", "application/x-dtbncx+xml"),
+                ]),
+            )?;
+        }
 
-        let cover = self.cover.as_ref().unwrap();
         manifest_add_image(
             &mut xml_writer,
             cover.image_file_name().as_str(),
             cover.mime_type,
             true,
         )?;
-        manifest_add_page(&mut xml_writer, cover.cover_file_name().as_str())?;
+        manifest_add_page(&mut xml_writer, cover.cover_file_name().as_str(), true)?;
 
-        if self.cover_spacer_required {
-            manifest_add_page(&mut xml_writer, cover.spacer_file_name().as_str())?;
+        if self.pages.cover_spacer_required {
+            manifest_add_page(&mut xml_writer, cover.spacer_file_name().as_str(), true)?;
         }
 
-        for i in self.images.iter() {
-            manifest_add_image(
-                &mut xml_writer,
-                i.image_file_name().as_str(),
-                i.mime_type,
-                false,
-            )?;
+        for i in self.pages.images.iter() {
+            if i.has_image && i.duplicate_of.is_none() {
+                manifest_add_image(
+                    &mut xml_writer,
+                    i.image_file_name().as_str(),
+                    i.mime_type,
+                    false,
+                )?;
+            }
             for j in i.page_file_names(self.metadata.right_to_left).iter() {
-                manifest_add_page(&mut xml_writer, j.as_str())?;
+                manifest_add_page(&mut xml_writer, j.as_str(), i.text_body.is_none())?;
+            }
+        }
+
+        let back_cover = self.pages.back_cover.as_ref();
+        if let Some(d) = back_cover {
+            if d.duplicate_of.is_none() {
+                manifest_add_image(
+                    &mut xml_writer,
+                    d.image_file_name().as_str(),
+                    d.mime_type,
+                    false,
+                )?;
             }
+            manifest_add_page(&mut xml_writer, d.cover_file_name().as_str(), true)?;
         }
 
         xml_writer.write(XmlEvent::end_element())?;
 
-        xml_writer.write(XmlEvent::start_element("spine").attr(
+        let mut spine_event = XmlEvent::start_element("spine").attr(
             "page-progression-direction",
             match self.metadata.right_to_left {
                 true => "rtl",
                 false => "ltr",
             },
-        ))?;
+        );
+        if self.options.epub2_compatibility {
+            spine_event = spine_event.attr("toc", "ncx");
+        }
+        xml_writer.write(spine_event)?;
 
-        add_element(
-            &mut xml_writer,
-            "itemref",
-            None,
-            Some(vec![("idref", cover.cover_file_name().as_str())]),
-        )?;
+        // The back cover closes the last spread, on its trailing side
+        let back_cover_file_name = back_cover.map(|d| d.cover_file_name());
+        let back_cover_spread_side = match self.metadata.right_to_left {
+            true => "page-spread-right",
+            false => "page-spread-left",
+        };
+        for i in self
+            .pages
+            .spine_file_names(self.metadata.right_to_left)
+            .iter()
+        {
+            let mut attributes = vec![("idref", i.as_str())];
+            if back_cover_file_name.as_ref() == Some(i) {
+                attributes.push(("properties", back_cover_spread_side));
+            }
+            add_element(&mut xml_writer, "itemref", None, Some(attributes))?;
+        }
 
-        if self.cover_spacer_required {
+        xml_writer.write(XmlEvent::end_element())?;
+
+        if self.options.epub2_compatibility {
+            xml_writer.write(XmlEvent::start_element("guide"))?;
             add_element(
                 &mut xml_writer,
-                "itemref",
+                "reference",
                 None,
-                Some(vec![("idref", cover.spacer_file_name().as_str())]),
+                Some(vec![
+                    ("type", "cover"),
+                    ("title", "Cover"),
+                    ("href", cover.cover_file_name().as_str()),
+                ]),
             )?;
-        }
-
-        for i in self.images.iter() {
-            for j in i.page_file_names(self.metadata.right_to_left).iter() {
+            add_element(
+                &mut xml_writer,
+                "reference",
+                None,
+                Some(vec![
+                    ("type", "text"),
+                    ("title", "Start of reading"),
+                    (
+                        "href",
+                        self.pages.images[0]
+                            .page_file_names(self.metadata.right_to_left)
+                            .remove(0)
+                            .as_str(),
+                    ),
+                ]),
+            )?;
+            if let Some(d) = back_cover_file_name.as_ref() {
                 add_element(
                     &mut xml_writer,
-                    "itemref",
+                    "reference",
                     None,
-                    Some(vec![("idref", j.as_str())]),
+                    Some(vec![
+                        ("type", "other.backcover"),
+                        ("title", "Back cover"),
+                        ("href", d.as_str()),
+                    ]),
                 )?;
             }
+            xml_writer.write(XmlEvent::end_element())?;
         }
 
-        xml_writer.write(XmlEvent::end_element())?;
-
         xml_writer.write(XmlEvent::end_element())?;
         return Ok(buffer);
     }
 
     fn generate_nav_xml(&mut self) -> xml::writer::Result<Vec<u8>> {
+        fn add_nav_entry<W: Write>(
+            writer: &mut EventWriter<W>,
+            label: &str,
+            href: &str,
+            epub_type: Option<&str>,
+        ) -> xml::writer::Result<()> {
+            let mut xml_event = XmlEvent::start_element("a").attr("href", href);
+            if let Some(d) = epub_type {
+                xml_event = xml_event.attr("epub:type", d);
+            }
+
+            writer.write(XmlEvent::start_element("li"))?;
+            writer.write(xml_event)?;
+            writer.write(XmlEvent::characters(label))?;
+            writer.write(XmlEvent::end_element())?;
+            writer.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+
         let mut buffer = Vec::<u8>::new();
         let mut xml_writer = EventWriter::new_with_config(
             Cursor::new(&mut buffer),
@@ -473,51 +868,525 @@ impl<W: Write + Seek> EpubWriter<W> {
         )?;
         xml_writer.write(XmlEvent::start_element("ol"))?;
 
-        let bookmarks: Vec<_> = self
+        for (label, href) in self.nav_bookmarks().iter() {
+            add_nav_entry(&mut xml_writer, label, href, None)?;
+        }
+
+        xml_writer.write(XmlEvent::end_element())?;
+        xml_writer.write(XmlEvent::end_element())?;
+
+        xml_writer.write(
+            XmlEvent::start_element("nav")
+                .attr("id", "landmarks")
+                .attr("epub:type", "landmarks")
+                .attr("hidden", "hidden"),
+        )?;
+        xml_writer.write(XmlEvent::start_element("ol"))?;
+        for (epub_type, label, href) in self.nav_landmarks().iter() {
+            add_nav_entry(&mut xml_writer, label, href, Some(epub_type))?;
+        }
+        xml_writer.write(XmlEvent::end_element())?;
+        xml_writer.write(XmlEvent::end_element())?;
+
+        let page_list: Vec<_> = self
+            .pages
             .images
             .iter()
-            .filter(|&d| d.nav_label.is_some())
+            .flat_map(|d| d.page_list_entries(self.metadata.right_to_left))
             .collect();
-        if bookmarks.is_empty() {
-            xml_writer.write(XmlEvent::start_element("li"))?;
-            xml_writer.write(XmlEvent::start_element("a").attr(
-                "href",
-                self.cover.as_ref().unwrap().cover_file_name().as_str(),
-            ))?;
-            xml_writer.write(XmlEvent::characters(self.metadata.title.as_str()))?;
+        if !page_list.is_empty() {
+            xml_writer.write(
+                XmlEvent::start_element("nav")
+                    .attr("id", "page-list")
+                    .attr("epub:type", "page-list")
+                    .attr("hidden", "hidden"),
+            )?;
+            xml_writer.write(XmlEvent::start_element("ol"))?;
+            for (label, href) in page_list.iter() {
+                add_nav_entry(&mut xml_writer, label, href, None)?;
+            }
             xml_writer.write(XmlEvent::end_element())?;
             xml_writer.write(XmlEvent::end_element())?;
-        } else {
-            for i in bookmarks.into_iter() {
-                xml_writer.write(XmlEvent::start_element("li"))?;
-                xml_writer.write(
-                    XmlEvent::start_element("a").attr(
-                        "href",
-                        i.page_file_names(self.metadata.right_to_left)
-                            .first()
-                            .unwrap()
-                            .as_str(),
-                    ),
-                )?;
-                xml_writer.write(XmlEvent::characters(i.nav_label.as_ref().unwrap().as_str()))?;
-                xml_writer.write(XmlEvent::end_element())?;
-                xml_writer.write(XmlEvent::end_element())?;
-            }
         }
 
         xml_writer.write(XmlEvent::end_element())?;
+
         xml_writer.write(XmlEvent::end_element())?;
+
+        return Ok(buffer);
+    }
+
+    fn generate_toc_ncx(&mut self) -> xml::writer::Result<Vec<u8>> {
+        fn add_text_element<W: Write>(
+            writer: &mut EventWriter<W>,
+            name: &str,
+            content: &str,
+        ) -> xml::writer::Result<()> {
+            writer.write(XmlEvent::start_element(name))?;
+            writer.write(XmlEvent::start_element("text"))?;
+            writer.write(XmlEvent::characters(content))?;
+            writer.write(XmlEvent::end_element())?;
+            writer.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+
+        fn add_meta<W: Write>(
+            writer: &mut EventWriter<W>,
+            name: &str,
+            content: &str,
+        ) -> xml::writer::Result<()> {
+            writer.write(
+                XmlEvent::start_element("meta")
+                    .attr("name", name)
+                    .attr("content", content),
+            )?;
+            writer.write(XmlEvent::end_element())?;
+            Ok(())
+        }
+
+        let mut buffer = Vec::<u8>::new();
+        let mut xml_writer = EventWriter::new_with_config(
+            Cursor::new(&mut buffer),
+            EmitterConfig {
+                perform_indent: true,
+                ..Default::default()
+            },
+        );
+
+        xml_writer.write(
+            XmlEvent::start_element("ncx")
+                .default_ns("http://www.daisy.org/z3986/2005/ncx/")
+                .attr("version", "2005-1")
+                .attr("xml:lang", self.metadata.language.as_str()),
+        )?;
+
+        xml_writer.write(XmlEvent::start_element("head"))?;
+        add_meta(&mut xml_writer, "dtb:uid", self.metadata.id.as_str())?;
+        add_meta(&mut xml_writer, "dtb:depth", "1")?;
+        add_meta(&mut xml_writer, "dtb:totalPageCount", "0")?;
+        add_meta(&mut xml_writer, "dtb:maxPageNumber", "0")?;
         xml_writer.write(XmlEvent::end_element())?;
 
+        add_text_element(&mut xml_writer, "docTitle", self.metadata.title.as_str())?;
+        add_text_element(&mut xml_writer, "docAuthor", self.metadata.author.as_str())?;
+
+        xml_writer.write(XmlEvent::start_element("navMap"))?;
+        for (i, (label, href)) in self.nav_bookmarks().iter().enumerate() {
+            let id = format!("navPoint{}", i + 1);
+            let play_order = (i + 1).to_string();
+            xml_writer.write(
+                XmlEvent::start_element("navPoint")
+                    .attr("id", id.as_str())
+                    .attr("playOrder", play_order.as_str()),
+            )?;
+            add_text_element(&mut xml_writer, "navLabel", label)?;
+            xml_writer.write(XmlEvent::start_element("content").attr("src", href))?;
+            xml_writer.write(XmlEvent::end_element())?;
+            xml_writer.write(XmlEvent::end_element())?;
+        }
         xml_writer.write(XmlEvent::end_element())?;
 
+        xml_writer.write(XmlEvent::end_element())?;
         return Ok(buffer);
     }
 
+    /// Labels and page document hrefs of table of contents entries,
+    /// falling back to a single entry for the cover when no page is labelled.
+    fn nav_bookmarks(&self) -> Vec<(String, String)> {
+        let output: Vec<_> = self
+            .pages
+            .images
+            .iter()
+            .filter(|&d| d.nav_label.is_some())
+            .map(|d| {
+                (
+                    d.nav_label.clone().unwrap(),
+                    d.page_file_names(self.metadata.right_to_left)
+                        .first()
+                        .unwrap()
+                        .clone(),
+                )
+            })
+            .collect();
+        if !output.is_empty() {
+            return output;
+        }
+
+        let cover = self.pages.cover.as_ref().unwrap();
+        return vec![(self.metadata.title.clone(), cover.cover_file_name())];
+    }
+
+    /// Landmark types, labels and page document hrefs for cover, start of reading, back matter
+    /// and back cover. There being no landmark type for back covers, they are marked as back matter.
+    fn nav_landmarks(&self) -> Vec<(&'static str, String, String)> {
+        let rtl = self.metadata.right_to_left;
+        let cover = self.pages.cover.as_ref().unwrap();
+        let mut output = vec![
+            ("cover", "Cover".to_string(), cover.cover_file_name()),
+            (
+                "bodymatter",
+                "Start of reading".to_string(),
+                self.pages.images[0].page_file_names(rtl).remove(0),
+            ),
+        ];
+
+        if let Some(d) = self.pages.images.iter().find(|&d| d.back_matter) {
+            output.push((
+                "backmatter",
+                "Back matter".to_string(),
+                d.page_file_names(rtl).remove(0),
+            ));
+        }
+        if let Some(d) = self.pages.back_cover.as_ref() {
+            output.push(("backmatter", "Back cover".to_string(), d.cover_file_name()));
+        }
+
+        return output;
+    }
+
+    fn add_image_entry(&mut self, name: &str, data: &[u8]) -> Result<(), EpubWriterError> {
+        let method = self.options.compression_policy.compression_method(true);
+        return self.write_zip_entry(name, data, method);
+    }
+
     fn add_zip_entry(&mut self, name: &str, data: &[u8]) -> Result<(), EpubWriterError> {
-        let options = zip::write::FileOptions::default();
+        let method = self.options.compression_policy.compression_method(false);
+        return self.write_zip_entry(name, data, method);
+    }
+
+    fn write_zip_entry(
+        &mut self,
+        name: &str,
+        data: &[u8],
+        compression_method: zip::CompressionMethod,
+    ) -> Result<(), EpubWriterError> {
+        let max_entry_size = max_entry_size(name, data.len() as u64);
+        let large_file = max_entry_size >= ZIP64_BYTES_LIMIT;
+        self.check_zip64_limits(1, max_entry_size)?;
+
+        let mut options = zip::write::SimpleFileOptions::default()
+            .compression_method(compression_method)
+            .large_file(large_file);
+        if compression_method == zip::CompressionMethod::Deflated {
+            options = options.compression_level(self.options.compression_level.map(i64::from));
+        }
+
         self.inner.start_file(name, options)?;
-        self.inner.write_all(&data)?;
+        self.inner.write_all(data)?;
+        self.zip_entries += 1;
+        return Ok(());
+    }
+
+    /// Fails when ZIP64 records are disabled and adding entries of up to the given total size
+    /// would need them
+    fn check_zip64_limits(&self, entries: usize, size: u64) -> Result<(), EpubWriterError> {
+        if !self.options.disable_zip64 {
+            return Ok(());
+        }
+
+        let archive_size = self.events.bytes_written().load(Ordering::Relaxed);
+        if archive_size + size >= ZIP64_BYTES_LIMIT
+            || self.zip_entries + entries > ZIP64_ENTRIES_LIMIT
+        {
+            return Err(EpubWriterError::Zip64RequiredError);
+        }
+
         return Ok(());
     }
 }
+
+/// Upper bound for the size of an entry once written, headers included: deflate never grows
+/// data by more than a few bytes every 16 KiB block
+fn max_entry_size(name: &str, data_size: u64) -> u64 {
+    return 128 + name.len() as u64 + data_size * 1001 / 1000;
+}
+
+fn generate_apple_display_options_xml(
+    options: &AppleDisplayOptions,
+) -> xml::writer::Result<Vec<u8>> {
+    fn add_option<W: Write>(
+        writer: &mut EventWriter<W>,
+        name: &str,
+        value: &str,
+    ) -> xml::writer::Result<()> {
+        writer.write(XmlEvent::start_element("option").attr("name", name))?;
+        writer.write(XmlEvent::characters(value))?;
+        writer.write(XmlEvent::end_element())?;
+        Ok(())
+    }
+
+    let mut buffer = Vec::<u8>::new();
+    let mut xml_writer = EventWriter::new_with_config(
+        Cursor::new(&mut buffer),
+        EmitterConfig {
+            perform_indent: true,
+            ..Default::default()
+        },
+    );
+
+    xml_writer.write(XmlEvent::start_element("display_options"))?;
+    xml_writer.write(XmlEvent::start_element("platform").attr("name", "*"))?;
+    add_option(&mut xml_writer, "fixed-layout", "true")?;
+    add_option(
+        &mut xml_writer,
+        "open-to-spread",
+        if options.open_to_spread {
+            "true"
+        } else {
+            "false"
+        },
+    )?;
+    add_option(
+        &mut xml_writer,
+        "orientation-lock",
+        options.orientation_lock.display_option_value(),
+    )?;
+    xml_writer.write(XmlEvent::end_element())?;
+    xml_writer.write(XmlEvent::end_element())?;
+
+    return Ok(buffer);
+}
+
+impl<W: Write> BookWriter for EpubWriter<W> {
+    fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        EpubWriter::set_observer(self, observer);
+    }
+
+    fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return EpubWriter::set_prepared_cover(self, image);
+    }
+
+    fn set_prepared_back_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return EpubWriter::set_prepared_back_cover(self, image);
+    }
+
+    fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return EpubWriter::add_prepared_image(self, image, options);
+    }
+
+    fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        return EpubWriter::finalize(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+    use xml::reader::{EventReader, XmlEvent as ReaderEvent};
+
+    /// PNG image filled with a single shade, so that images differ by shade
+    fn png(width: u32, height: u32, shade: u8) -> PreparedImage {
+        let image = RgbImage::from_pixel(width, height, Rgb([shade, shade, shade]));
+        let mut data = Vec::<u8>::new();
+        DynamicImage::ImageRgb8(image)
+            .write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)
+            .unwrap();
+        return PreparedImage::new(data).unwrap();
+    }
+
+    fn labelled(label: &str) -> PageOptions {
+        return PageOptions {
+            nav_label: Some(label.to_string()),
+            ..Default::default()
+        };
+    }
+
+    fn read_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Vec<u8> {
+        let mut output = Vec::<u8>::new();
+        archive
+            .by_name(name)
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        return output;
+    }
+
+    /// Entry names in archive order
+    fn entry_names<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Vec<String> {
+        return (0..archive.len())
+            .map(|d| archive.by_index_raw(d).unwrap().name().unwrap().to_string())
+            .collect();
+    }
+
+    fn spine(package: &[u8]) -> Vec<String> {
+        let mut output = Vec::<String>::new();
+        for i in EventReader::new(package) {
+            if let ReaderEvent::StartElement {
+                name, attributes, ..
+            } = i.unwrap()
+            {
+                if name.local_name == "itemref" {
+                    let idref = attributes.iter().find(|d| d.name.local_name == "idref");
+                    output.push(idref.unwrap().value.clone());
+                }
+            }
+        }
+        return output;
+    }
+
+    #[test]
+    fn zip64_limits_checked_before_writing_pages() {
+        let options = EpubWriterOptions {
+            disable_zip64: true,
+            ..Default::default()
+        };
+        let mut output = Cursor::new(Vec::<u8>::new());
+        let mut writer =
+            EpubWriter::new_with_options(&mut output, Metadata::default(), options).unwrap();
+        writer.set_prepared_cover(png(2, 3, 10)).unwrap();
+        writer
+            .add_prepared_image(png(2, 3, 20), PageOptions::default())
+            .unwrap();
+
+        // Room left for the package document and navigation only, a spread needing three
+        // entries for its image and halves
+        writer.zip_entries = ZIP64_ENTRIES_LIMIT - 2;
+        let bytes_written = writer.events.bytes_written().load(Ordering::Relaxed);
+        match writer.add_prepared_image(png(6, 3, 30), PageOptions::default()) {
+            Err(EpubWriterError::Zip64RequiredError) => {}
+            Err(d) => panic!("unexpected error {}", d),
+            Ok(()) => panic!("page added beyond ZIP64 limits"),
+        }
+        assert_eq!(writer.zip_entries, ZIP64_ENTRIES_LIMIT - 2);
+        assert_eq!(
+            writer.events.bytes_written().load(Ordering::Relaxed),
+            bytes_written
+        );
+        assert_eq!(writer.pages.images.len(), 1);
+        assert!(!writer.pages.cover_spacer_required);
+
+        writer.finalize().unwrap();
+        drop(writer);
+
+        let mut archive = zip::ZipArchive::new(output).unwrap();
+        assert!(entry_names(&mut archive)
+            .iter()
+            .all(|d| !d.contains("S01-C000001P000002") && !d.contains("Spacer")));
+        assert_eq!(
+            spine(&read_entry(&mut archive, "OEBPS/content.opf")),
+            ["S00-Cover.xhtml", "S01-C000001P000001.xhtml"]
+        );
+    }
+
+    #[test]
+    fn appending_continues_numbering_and_spread_parity() {
+        // The spread follows a single page, so a spacer after the cover keeps it facing
+        let mut existing = Cursor::new(Vec::<u8>::new());
+        let mut writer = EpubWriter::new(&mut existing, Metadata::default()).unwrap();
+        writer.set_prepared_cover(png(2, 3, 10)).unwrap();
+        writer
+            .add_prepared_image(png(2, 3, 20), labelled("One"))
+            .unwrap();
+        writer
+            .add_prepared_image(png(6, 3, 30), PageOptions::default())
+            .unwrap();
+        writer
+            .add_prepared_image(png(2, 3, 40), PageOptions::default())
+            .unwrap();
+        writer
+            .add_prepared_image(png(2, 3, 20), PageOptions::default())
+            .unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+        let existing = existing.into_inner();
+
+        let mut appended = Cursor::new(Vec::<u8>::new());
+        let metadata = Metadata::from_epub(Cursor::new(&existing)).unwrap();
+        let mut writer = EpubWriter::new_appending(
+            Cursor::new(&existing),
+            &mut appended,
+            metadata,
+            EpubWriterOptions::default(),
+        )
+        .unwrap();
+
+        // Seven pages including the spacer, so a spread would no longer face
+        writer
+            .add_prepared_image(png(2, 3, 50), labelled("Two"))
+            .unwrap();
+        match writer.add_prepared_image(png(6, 3, 60), PageOptions::default()) {
+            Err(EpubWriterError::PageSortingError { page_number: 6 }) => {}
+            Err(d) => panic!("unexpected error {}", d),
+            Ok(()) => panic!("spread accepted on the wrong side"),
+        }
+        writer
+            .add_prepared_image(png(2, 3, 60), PageOptions::default())
+            .unwrap();
+        writer
+            .add_prepared_image(png(6, 3, 70), PageOptions::default())
+            .unwrap();
+        writer
+            .add_prepared_image(png(2, 3, 40), PageOptions::default())
+            .unwrap();
+        writer.finalize().unwrap();
+        drop(writer);
+
+        let mut existing = zip::ZipArchive::new(Cursor::new(existing)).unwrap();
+        let mut appended = zip::ZipArchive::new(appended).unwrap();
+
+        assert_eq!(
+            spine(&read_entry(&mut appended, "OEBPS/content.opf")),
+            [
+                "S00-Cover.xhtml",
+                "S00-Cover_Spacer.xhtml",
+                "S01-C000001P000001.xhtml",
+                "S01-C000001P000002_L.xhtml",
+                "S01-C000001P000002_R.xhtml",
+                "S01-C000001P000003.xhtml",
+                "S01-C000001P000004.xhtml",
+                "S01-C000002P000001.xhtml",
+                "S01-C000002P000002.xhtml",
+                "S01-C000002P000003_L.xhtml",
+                "S01-C000002P000003_R.xhtml",
+                "S01-C000002P000004.xhtml",
+            ]
+        );
+
+        // Entries of the existing book come first and unchanged, only the spacer, package
+        // document and navigation being written again
+        let existing_names = entry_names(&mut existing);
+        let appended_names = entry_names(&mut appended);
+        let copied_names = existing_names
+            .iter()
+            .filter(|d| !ExistingBook::is_regenerated(d))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(appended_names[..copied_names.len()], copied_names[..]);
+        for i in copied_names.iter() {
+            assert_eq!(
+                read_entry(&mut existing, i),
+                read_entry(&mut appended, i),
+                "{}",
+                i
+            );
+        }
+        for i in existing_names
+            .iter()
+            .filter(|d| ExistingBook::is_regenerated(d))
+        {
+            assert_eq!(
+                appended_names.iter().filter(|d| *d == i).count(),
+                1,
+                "{}",
+                i
+            );
+        }
+
+        // Images already in the book are referenced rather than written again
+        assert!(appended_names.contains(&"OEBPS/S01-C000002P000003.png".to_string()));
+        assert!(!appended_names.contains(&"OEBPS/S01-C000002P000004.png".to_string()));
+        let page = read_entry(&mut appended, "OEBPS/S01-C000002P000004.xhtml");
+        assert!(String::from_utf8(page)
+            .unwrap()
+            .contains("S01-C000001P000003.png"));
+
+        let navigation = String::from_utf8(read_entry(&mut appended, "OEBPS/nav.xhtml")).unwrap();
+        assert!(navigation.contains("<a href=\"S01-C000001P000001.xhtml\">One</a>"));
+        assert!(navigation.contains("<a href=\"S01-C000002P000001.xhtml\">Two</a>"));
+    }
+}
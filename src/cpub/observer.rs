@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Events reported by book writers while a book is generated
+pub enum EpubWriterEvent<'a> {
+    /// The cover was added, `file_name` being the name of its image inside the book
+    CoverSet {
+        file_name: &'a str,
+    },
+    /// The back cover was added, `file_name` being the name of its image inside the book
+    BackCoverSet {
+        file_name: &'a str,
+    },
+    /// A page was added, `index` counting pages after the cover from 0
+    PageAdded {
+        index: usize,
+        file_name: &'a str,
+    },
+    /// The page at `index` is a spread, occupying two page positions
+    SpreadDetected {
+        index: usize,
+        size: (u32, u32),
+    },
+    /// A blank page follows the cover so that spreads face each other
+    SpacerInserted,
+    /// Total number of bytes written to the output so far
+    BytesWritten {
+        total: u64,
+    },
+    Finalizing(FinalizePhase),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FinalizePhase {
+    Started,
+    MetadataWritten,
+    NavigationWritten,
+    Completed,
+}
+
+/// Receives writer events. Implemented for closures taking an event.
+pub trait EpubWriterObserver {
+    fn on_event(&mut self, event: &EpubWriterEvent);
+}
+
+impl<F: FnMut(&EpubWriterEvent)> EpubWriterObserver for F {
+    fn on_event(&mut self, event: &EpubWriterEvent) {
+        self(event);
+    }
+}
+
+/// Holds a writer's observer, if any, together with the count of bytes written to its output
+pub struct EventDispatcher {
+    observer: Option<Box<dyn EpubWriterObserver + Send>>,
+    bytes_written: Arc<AtomicU64>,
+    bytes_reported: u64,
+}
+
+impl EventDispatcher {
+    pub fn new() -> EventDispatcher {
+        EventDispatcher {
+            observer: None,
+            bytes_written: Arc::new(AtomicU64::new(0)),
+            bytes_reported: 0,
+        }
+    }
+
+    pub fn bytes_written(&self) -> Arc<AtomicU64> {
+        return self.bytes_written.clone();
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        self.observer = Some(observer);
+    }
+
+    pub fn notify(&mut self, event: EpubWriterEvent) {
+        if let Some(d) = self.observer.as_mut() {
+            d.on_event(&event);
+        }
+    }
+
+    /// Reports the bytes written since the last call, if any
+    pub fn notify_bytes_written(&mut self) {
+        let total = self.bytes_written.load(Ordering::Relaxed);
+        if total != self.bytes_reported {
+            self.bytes_reported = total;
+            self.notify(EpubWriterEvent::BytesWritten { total });
+        }
+    }
+}
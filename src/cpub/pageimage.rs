@@ -1,19 +1,49 @@
-use super::{errors::EpubWriterError, templates};
-use image::{GenericImageView, ImageFormat};
+use super::pagetemplates::{fill_template, PageTemplates};
+use super::{errors::EpubWriterError, metadata::Metadata, templates};
+use image::codecs::{gif::GifDecoder, jpeg::JpegDecoder, png::PngDecoder};
+use image::{ColorType, DynamicImage, ImageDecoder, ImageFormat, ImageResult};
+use sha2::{Digest, Sha256};
+use std::io::Cursor;
+use xml::escape::escape_str_attribute;
 
+#[derive(Clone)]
 pub struct PageImage {
     pub base_name: String,
     pub nav_label: Option<String>,
+    pub page_number: Option<String>,
+    pub back_matter: bool,
+    pub back_cover: bool,
+    /// Text alternative for the page image
+    pub description: Option<String>,
+    pub format: ImageFormat,
     pub extension: &'static str,
     pub mime_type: &'static str,
     pub size: (u32, u32),
+    pub color_type: ColorType,
     pub spread: bool,
+    /// SHA-256 digest of the image data, telling apart identical images
+    pub content_hash: [u8; 32],
+    /// Base name of an identical image already added, whose file is referenced instead
+    pub duplicate_of: Option<String>,
+    /// False for text pages without an image, which have no image file
+    pub has_image: bool,
+    /// Escaped XHTML body of text pages
+    pub text_body: Option<String>,
+    /// Navigation label of the chapter the page belongs to
+    pub chapter_label: Option<String>,
+    /// Position of the first page document in reading order, None for the cover
+    pub first_page: Option<u32>,
 }
 
 impl PageImage {
+    /// Reads format, dimensions and colour type from the image header only,
+    /// pixel data is left untouched until `decode` is called.
     pub fn new(image_data: &[u8], nav_label: Option<String>) -> Result<PageImage, EpubWriterError> {
-        let imgfmt = image::guess_format(&image_data)
-            .map_err(|source| EpubWriterError::InvalidImageError(source))?;
+        fn probe<'a, D: ImageDecoder<'a>>(decoder: D) -> ((u32, u32), ColorType) {
+            return (decoder.dimensions(), decoder.color_type());
+        }
+
+        let imgfmt = image::guess_format(image_data).map_err(EpubWriterError::InvalidImageError)?;
         let imgtypeinfo = match imgfmt {
             ImageFormat::Gif => (".gif", "image/gif"),
             ImageFormat::Jpeg => (".jpg", "image/jpeg"),
@@ -21,22 +51,75 @@ impl PageImage {
             _ => return Err(EpubWriterError::UnsupportedImageError),
         };
 
-        let img = image::load_from_memory(&image_data)
-            .map_err(|source| EpubWriterError::InvalidImageError(source))?;
-        let imgsize = img.dimensions();
+        let reader = Cursor::new(image_data);
+        let (imgsize, color_type) = match imgfmt {
+            ImageFormat::Gif => probe(GifDecoder::new(reader)?),
+            ImageFormat::Jpeg => probe(JpegDecoder::new(reader)?),
+            _ => probe(PngDecoder::new(reader)?),
+        };
 
         return Ok(PageImage {
             base_name: String::new(),
-            nav_label: nav_label,
+            nav_label,
+            page_number: None,
+            back_matter: false,
+            back_cover: false,
+            description: None,
+            format: imgfmt,
             extension: imgtypeinfo.0,
             mime_type: imgtypeinfo.1,
             size: imgsize,
+            color_type,
             spread: imgsize.0 > imgsize.1,
+            content_hash: Sha256::digest(image_data).into(),
+            duplicate_of: None,
+            has_image: true,
+            text_body: None,
+            chapter_label: None,
+            first_page: None,
         });
     }
 
+    /// Text page without an image, sized to the given viewport
+    pub fn new_text_page(size: (u32, u32), text_body: String) -> PageImage {
+        return PageImage {
+            base_name: String::new(),
+            nav_label: None,
+            page_number: None,
+            back_matter: false,
+            back_cover: false,
+            description: None,
+            format: ImageFormat::Png,
+            extension: "",
+            mime_type: "",
+            size,
+            color_type: ColorType::Rgb8,
+            spread: false,
+            content_hash: [0; 32],
+            duplicate_of: None,
+            has_image: false,
+            text_body: Some(text_body),
+            chapter_label: None,
+            first_page: None,
+        };
+    }
+
+    /// Size of each page document, halves of spreads being shown as separate pages
+    pub fn viewport_size(&self) -> (u32, u32) {
+        match self.spread {
+            true => (self.size.0 / 2, self.size.1),
+            false => self.size,
+        }
+    }
+
+    /// Fully decodes the image, for processing stages needing pixel data
+    pub fn decode(&self, image_data: &[u8]) -> ImageResult<DynamicImage> {
+        return image::load_from_memory_with_format(image_data, self.format);
+    }
+
     pub fn image_file_name(&self) -> String {
-        format!("{}{}", self.base_name, self.extension)
+        let base_name = self.duplicate_of.as_ref().unwrap_or(&self.base_name);
+        format!("{}{}", base_name, self.extension)
     }
 
     pub fn cover_file_name(&self) -> String {
@@ -62,37 +145,137 @@ impl PageImage {
         }
     }
 
-    pub fn generate_pages_xml(&self, reading_rtl: bool) -> Vec<(String, String)> {
+    /// Printed page numbers paired with the page documents they refer to
+    pub fn page_list_entries(&self, reading_rtl: bool) -> Vec<(String, String)> {
+        let page_number = match self.page_number.as_ref() {
+            Some(d) => d,
+            None => return Vec::new(),
+        };
+
+        let mut file_names = self.page_file_names(reading_rtl).into_iter();
+        let mut output = vec![(page_number.clone(), file_names.next().unwrap())];
+        if let (Some(file_name), Ok(number)) = (file_names.next(), page_number.parse::<u32>()) {
+            output.push(((number + 1).to_string(), file_name));
+        }
+
+        return output;
+    }
+
+    pub fn generate_pages_xml(
+        &self,
+        metadata: &Metadata,
+        templates: &PageTemplates,
+    ) -> Vec<(String, String)> {
+        return self.generate_pages_xml_with_href(metadata, templates, &self.image_file_name());
+    }
+
+    pub fn generate_pages_xml_with_href(
+        &self,
+        metadata: &Metadata,
+        templates: &PageTemplates,
+        image_href: &str,
+    ) -> Vec<(String, String)> {
+        if let Some(text_body) = self.text_body.as_ref() {
+            return vec![(
+                self.page_regular_file_name(),
+                self.generate_text_page_xml(text_body, metadata, image_href),
+            )];
+        }
+
+        let mut titles = self.page_titles(metadata).into_iter();
         if self.spread {
+            let (mut left_title, mut right_title) = (titles.next(), titles.next());
+            if metadata.right_to_left {
+                std::mem::swap(&mut left_title, &mut right_title);
+            }
+
             let mut output = vec![
                 (
                     self.page_spread_left_file_name(),
-                    self.generate_page_xml(templates::PAGE_SPREAD_L_XML),
+                    self.generate_page_xml(
+                        &templates.spread_left,
+                        metadata,
+                        &left_title.unwrap(),
+                        image_href,
+                    ),
                 ),
                 (
                     self.page_spread_right_file_name(),
-                    self.generate_page_xml(templates::PAGE_SPREAD_R_XML),
+                    self.generate_page_xml(
+                        &templates.spread_right,
+                        metadata,
+                        &right_title.unwrap(),
+                        image_href,
+                    ),
                 ),
             ];
-            if reading_rtl {
+            if metadata.right_to_left {
                 output.reverse();
             }
             return output;
         } else {
             vec![(
                 self.page_regular_file_name(),
-                self.generate_page_xml(templates::PAGE_REGULAR_XML),
+                self.generate_page_xml(
+                    &templates.regular,
+                    metadata,
+                    &titles.next().unwrap(),
+                    image_href,
+                ),
             )]
         }
     }
 
-    pub fn generate_spacer_page(&self) -> (String, String) {
+    pub fn generate_spacer_page(
+        &self,
+        metadata: &Metadata,
+        templates: &PageTemplates,
+    ) -> (String, String) {
         (
             self.page_spacer_file_name(),
-            self.generate_page_xml(templates::PAGE_SPACER_XML),
+            self.generate_page_xml(
+                &templates.spacer,
+                metadata,
+                &metadata.title,
+                &self.image_file_name(),
+            ),
         )
     }
 
+    /// Titles of page documents in reading order: the book title followed by
+    /// the chapter and printed page number, or just "Cover" for covers
+    fn page_titles(&self, metadata: &Metadata) -> Vec<String> {
+        let first_page = match (self.first_page, self.back_cover) {
+            (Some(d), _) => d,
+            (None, false) => return vec![format!("{} - Cover", metadata.title)],
+            (None, true) => return vec![format!("{} - Back cover", metadata.title)],
+        };
+
+        let page_count = match self.spread {
+            true => 2,
+            false => 1,
+        };
+        let numbers: Vec<String> = match self.page_number.as_ref() {
+            Some(d) => match d.parse::<u32>() {
+                Ok(number) => (number..number + page_count)
+                    .map(|d| d.to_string())
+                    .collect(),
+                Err(_) => vec![d.clone(); page_count as usize],
+            },
+            None => (first_page..first_page + page_count)
+                .map(|d| d.to_string())
+                .collect(),
+        };
+
+        return numbers
+            .into_iter()
+            .map(|d| match self.chapter_label.as_ref() {
+                Some(chapter) => format!("{} - {} - Page {}", metadata.title, chapter, d),
+                None => format!("{} - Page {}", metadata.title, d),
+            })
+            .collect();
+    }
+
     fn page_regular_file_name(&self) -> String {
         format!("{}.xhtml", self.base_name)
     }
@@ -109,11 +292,74 @@ impl PageImage {
         format!("{}_Spacer.xhtml", self.base_name)
     }
 
-    fn generate_page_xml(&self, template: &str) -> String {
-        template
-            .replace("IMGW", &format!("{}", &self.size.0))
-            .replace("IMGHW", &format!("{}", &self.size.0 / 2))
-            .replace("IMGH", &format!("{}", &self.size.1))
-            .replace("FILENAME", &self.image_file_name())
+    fn generate_text_page_xml(
+        &self,
+        text_body: &str,
+        metadata: &Metadata,
+        image_href: &str,
+    ) -> String {
+        let image = match self.has_image {
+            true => format!(
+                "      <img src=\"{}\" alt=\"{}\" />\n",
+                image_href,
+                escape_str_attribute(self.description.as_deref().unwrap_or_default())
+            ),
+            false => String::new(),
+        };
+
+        return fill_template(
+            templates::PAGE_TEXT_XML,
+            &[
+                ("width", &self.size.0.to_string()),
+                ("height", &self.size.1.to_string()),
+                ("font_size", &(self.size.1 / 45).max(1).to_string()),
+                (
+                    "title",
+                    &escape_str_attribute(&self.page_titles(metadata)[0]),
+                ),
+                ("language", &escape_str_attribute(&metadata.language)),
+                ("text_image", &image),
+                ("text_content", text_body),
+            ],
+        );
+    }
+
+    fn generate_page_xml(
+        &self,
+        template: &str,
+        metadata: &Metadata,
+        title: &str,
+        image_href: &str,
+    ) -> String {
+        let description = escape_str_attribute(self.description.as_deref().unwrap_or_default());
+        let (aria_label, svg_title) = match description.is_empty() {
+            true => (String::new(), String::new()),
+            false => (
+                format!(" role=\"img\" aria-label=\"{}\"", description),
+                format!("\n        <title>{}</title>", description),
+            ),
+        };
+
+        return fill_template(
+            template,
+            &[
+                ("image", image_href),
+                ("width", &self.size.0.to_string()),
+                ("height", &self.size.1.to_string()),
+                ("half_width", &(self.size.0 / 2).to_string()),
+                ("title", &escape_str_attribute(title)),
+                ("language", &escape_str_attribute(&metadata.language)),
+                (
+                    "direction",
+                    match metadata.right_to_left {
+                        true => "rtl",
+                        false => "ltr",
+                    },
+                ),
+                ("description", &description),
+                ("aria_label", &aria_label),
+                ("svg_title", &svg_title),
+            ],
+        );
     }
 }
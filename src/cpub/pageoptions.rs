@@ -0,0 +1,13 @@
+#[derive(Clone, Default)]
+pub struct PageOptions {
+    /// Table of contents entry starting at the page
+    pub nav_label: Option<String>,
+    /// Number printed on the page in the physical book, used for page navigation.
+    /// For spreads, numeric values are assigned to the first half in reading order
+    /// and incremented for the second one.
+    pub page_number: Option<String>,
+    /// Marks the page as the start of back matter such as credits or afterwords
+    pub back_matter: bool,
+    /// Text alternative describing the page, for screen readers
+    pub description: Option<String>,
+}
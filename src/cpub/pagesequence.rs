@@ -0,0 +1,195 @@
+use super::errors::EpubWriterError;
+use super::pageimage::PageImage;
+use super::pageoptions::PageOptions;
+
+pub const COVER_BASE_NAME: &str = "S00-Cover";
+pub const BACK_COVER_BASE_NAME: &str = "S02-BackCover";
+
+pub struct PageSequence {
+    pub cover: Option<PageImage>,
+    pub back_cover: Option<PageImage>,
+    pub images: Vec<PageImage>,
+    pub cover_spacer_required: bool,
+    spread_added: bool,
+    current_chapter_number: u32,
+    current_page_number: u32,
+    total_pages_number: u32,
+    current_chapter_label: Option<String>,
+    page_documents_number: u32,
+}
+
+impl PageSequence {
+    pub fn new() -> PageSequence {
+        PageSequence {
+            cover: None,
+            back_cover: None,
+            images: Vec::default(),
+            cover_spacer_required: false,
+            spread_added: false,
+            current_chapter_number: 0,
+            current_page_number: 0,
+            total_pages_number: 0,
+            current_chapter_label: None,
+            page_documents_number: 0,
+        }
+    }
+
+    pub fn set_cover(&mut self, mut page_image: PageImage) -> Result<&PageImage, EpubWriterError> {
+        if self.cover.is_some() {
+            return Err(EpubWriterError::CoverAlreadySetError);
+        }
+
+        if page_image.spread {
+            return Err(EpubWriterError::CoverSizeError);
+        }
+
+        page_image.base_name = COVER_BASE_NAME.to_string();
+        self.cover = Some(page_image);
+        return Ok(self.cover.as_ref().unwrap());
+    }
+
+    pub fn set_back_cover(
+        &mut self,
+        mut page_image: PageImage,
+    ) -> Result<&PageImage, EpubWriterError> {
+        if self.back_cover.is_some() {
+            return Err(EpubWriterError::BackCoverAlreadySetError);
+        }
+
+        if page_image.spread {
+            return Err(EpubWriterError::CoverSizeError);
+        }
+
+        page_image.base_name = BACK_COVER_BASE_NAME.to_string();
+        page_image.back_cover = true;
+        self.back_cover = Some(page_image);
+        return Ok(self.back_cover.as_ref().unwrap());
+    }
+
+    pub fn add_image(
+        &mut self,
+        mut page_image: PageImage,
+        options: PageOptions,
+    ) -> Result<&PageImage, EpubWriterError> {
+        page_image.nav_label = options.nav_label;
+        page_image.page_number = options.page_number;
+        page_image.back_matter = options.back_matter;
+        page_image.description = options.description;
+        if page_image.spread {
+            if self.total_pages_number.is_multiple_of(2) {
+                self.total_pages_number += 2;
+            } else if !self.spread_added {
+                self.cover_spacer_required = true;
+                self.total_pages_number += 1;
+            } else {
+                return Err(EpubWriterError::PageSortingError {
+                    page_number: (self.images.len() + 1) as u32,
+                });
+            }
+            self.spread_added = true;
+        } else {
+            self.total_pages_number += 1;
+        }
+
+        if page_image.nav_label.is_some() || self.current_page_number == 0 {
+            self.current_page_number += 1;
+            self.current_chapter_number = 0;
+        }
+        self.current_chapter_number += 1;
+
+        if page_image.nav_label.is_some() {
+            self.current_chapter_label = page_image.nav_label.clone();
+        }
+        page_image.chapter_label = self.current_chapter_label.clone();
+        page_image.first_page = Some(self.page_documents_number + 1);
+        self.page_documents_number += match page_image.spread {
+            true => 2,
+            false => 1,
+        };
+
+        page_image.base_name = format!(
+            "S01-C{:06}P{:06}",
+            self.current_page_number, self.current_chapter_number
+        );
+        self.images.push(page_image);
+        return Ok(self.images.last().unwrap());
+    }
+
+    /// Adds a page of a book written earlier, numbering continuing from its base name
+    /// so that pages added afterwards follow it
+    pub fn add_existing_image(&mut self, mut page_image: PageImage) -> Result<(), EpubWriterError> {
+        let (chapter_number, page_number) = match parse_page_base_name(&page_image.base_name) {
+            Some(d) => d,
+            None => {
+                return Err(EpubWriterError::ExistingBookError {
+                    reason: format!("unexpected page {}", page_image.base_name),
+                })
+            }
+        };
+
+        self.current_page_number = chapter_number;
+        self.current_chapter_number = page_number;
+        self.total_pages_number += match page_image.spread {
+            true => 2,
+            false => 1,
+        };
+        self.spread_added |= page_image.spread;
+
+        if page_image.nav_label.is_some() {
+            self.current_chapter_label = page_image.nav_label.clone();
+        }
+        page_image.chapter_label = self.current_chapter_label.clone();
+        page_image.first_page = Some(self.page_documents_number + 1);
+        self.page_documents_number += match page_image.spread {
+            true => 2,
+            false => 1,
+        };
+
+        self.images.push(page_image);
+        return Ok(());
+    }
+
+    /// Marks the blank page after the cover as present in a book written earlier
+    pub fn add_existing_cover_spacer(&mut self) {
+        self.cover_spacer_required = true;
+        self.total_pages_number += 1;
+    }
+
+    pub fn validate(&self) -> Result<(), EpubWriterError> {
+        if self.cover.is_none() {
+            return Err(EpubWriterError::CoverNotSetError);
+        }
+
+        if self.images.is_empty() {
+            return Err(EpubWriterError::NoPagesError);
+        }
+
+        return Ok(());
+    }
+
+    pub fn spine_file_names(&self, reading_rtl: bool) -> Vec<String> {
+        let mut output = Vec::<String>::new();
+        if let Some(cover) = self.cover.as_ref() {
+            output.push(cover.cover_file_name());
+            if self.cover_spacer_required {
+                output.push(cover.spacer_file_name());
+            }
+        }
+
+        for i in self.images.iter() {
+            output.append(&mut i.page_file_names(reading_rtl));
+        }
+        if let Some(back_cover) = self.back_cover.as_ref() {
+            output.push(back_cover.cover_file_name());
+        }
+
+        return output;
+    }
+}
+
+/// Chapter and page numbers of a page base name, as in `S01-C000001P000002`
+fn parse_page_base_name(base_name: &str) -> Option<(u32, u32)> {
+    let numbers = base_name.strip_prefix("S01-C")?;
+    let (chapter_number, page_number) = numbers.split_once('P')?;
+    return Some((chapter_number.parse().ok()?, page_number.parse().ok()?));
+}
@@ -0,0 +1,250 @@
+use super::errors::EpubWriterError;
+use super::templates;
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent};
+
+/// Placeholders available to page templates
+const PLACEHOLDERS: [&str; 10] = [
+    "image",
+    "width",
+    "height",
+    "half_width",
+    "title",
+    "language",
+    "direction",
+    "description",
+    "aria_label",
+    "svg_title",
+];
+
+/// Values filled into templates to check they are well formed
+const SAMPLE_VALUES: [(&str, &str); 10] = [
+    ("image", "image.png"),
+    ("width", "2"),
+    ("height", "1"),
+    ("half_width", "1"),
+    ("title", "Title"),
+    ("language", "en"),
+    ("direction", "ltr"),
+    ("description", "Description"),
+    ("aria_label", " role=\"img\" aria-label=\"Description\""),
+    ("svg_title", "<title>Description</title>"),
+];
+
+/// XHTML documents pages are generated from, in place of the built-in ones.
+///
+/// Templates are filled in for every page by replacing these placeholders:
+/// - `{{image}}`: image file name
+/// - `{{width}}`, `{{height}}`: image size in pixels
+/// - `{{half_width}}`: half the image width, the page width for halves of spreads
+/// - `{{title}}`: page title
+/// - `{{language}}`: book language
+/// - `{{direction}}`: reading direction, `ltr` or `rtl`
+/// - `{{description}}`: page description, empty if not set
+/// - `{{aria_label}}`: ` role="img" aria-label="…"` attributes with the page description, empty if not set
+/// - `{{svg_title}}`: `<title>` element with the page description, empty if not set
+#[derive(Clone)]
+pub struct PageTemplates {
+    pub regular: String,
+    pub spread_left: String,
+    pub spread_right: String,
+    /// Blank page inserted after the cover when needed to keep spreads facing
+    pub spacer: String,
+}
+
+impl Default for PageTemplates {
+    fn default() -> Self {
+        PageTemplates {
+            regular: templates::PAGE_REGULAR_XML.to_string(),
+            spread_left: templates::PAGE_SPREAD_L_XML.to_string(),
+            spread_right: templates::PAGE_SPREAD_R_XML.to_string(),
+            spacer: templates::PAGE_SPACER_XML.to_string(),
+        }
+    }
+}
+
+impl PageTemplates {
+    /// Loads templates from `regular.xhtml`, `spread_left.xhtml`, `spread_right.xhtml`
+    /// and `spacer.xhtml` in a directory, using built-in ones for missing files
+    pub fn from_dir(path: &Path) -> Result<PageTemplates, EpubWriterError> {
+        let mut output = PageTemplates::default();
+        for (file_name, template) in [
+            ("regular.xhtml", &mut output.regular),
+            ("spread_left.xhtml", &mut output.spread_left),
+            ("spread_right.xhtml", &mut output.spread_right),
+            ("spacer.xhtml", &mut output.spacer),
+        ] {
+            let template_path = path.join(file_name);
+            if template_path.is_file() {
+                *template = std::fs::read_to_string(template_path)?;
+            }
+        }
+
+        output.validate()?;
+        return Ok(output);
+    }
+
+    /// Checks templates are well formed and only use known placeholders
+    pub fn validate(&self) -> Result<(), EpubWriterError> {
+        for (name, template) in [
+            ("regular", &self.regular),
+            ("spread_left", &self.spread_left),
+            ("spread_right", &self.spread_right),
+            ("spacer", &self.spacer),
+        ] {
+            let error = |reason: String| EpubWriterError::PageTemplateError { name, reason };
+
+            let mut rest = template.as_str();
+            while let Some((placeholder, remainder)) = next_placeholder(rest) {
+                if placeholder.contains("{{") {
+                    return Err(error("unterminated placeholder".to_string()));
+                }
+                if !PLACEHOLDERS.contains(&placeholder) {
+                    return Err(error(format!(
+                        "unknown placeholder {{{{{}}}}}",
+                        placeholder
+                    )));
+                }
+                rest = remainder;
+            }
+            if rest.contains("{{") {
+                return Err(error("unterminated placeholder".to_string()));
+            }
+
+            // Placeholders can expand to attributes, so templates are checked once filled in
+            let sample = fill_template(template, &SAMPLE_VALUES);
+            let mut reader = EventReader::from_str(&sample);
+            loop {
+                match reader.next() {
+                    Ok(XmlEvent::EndDocument) => break,
+                    Ok(_) => {}
+                    Err(d) => return Err(error(d.to_string())),
+                }
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Replaces placeholders with their values in a single pass, so that values are never
+/// mistaken for placeholders themselves. Placeholders without a value are left as they are.
+pub fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let (placeholder, remainder) = match next_placeholder(rest) {
+            Some(d) => d,
+            None => break,
+        };
+
+        output.push_str(&rest[..start]);
+        match values.iter().find(|(name, _)| *name == placeholder) {
+            Some((_, value)) => output.push_str(value),
+            None => output.push_str(&rest[start..rest.len() - remainder.len()]),
+        }
+        rest = remainder;
+    }
+
+    output.push_str(rest);
+    return output;
+}
+
+/// Name of the first placeholder and the text following it
+fn next_placeholder(text: &str) -> Option<(&str, &str)> {
+    let start = text.find("{{")? + 2;
+    let end = start + text[start..].find("}}")?;
+    return Some((&text[start..end], &text[end + 2..]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGULAR: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+        <html xmlns=\"http://www.w3.org/1999/xhtml\" xml:lang=\"{{language}}\">\
+        <head><title>{{title}}</title></head>\
+        <body><img src=\"{{image}}\" width=\"{{width}}\"{{aria_label}}/></body></html>";
+
+    fn with_regular(regular: &str) -> PageTemplates {
+        return PageTemplates {
+            regular: regular.to_string(),
+            ..Default::default()
+        };
+    }
+
+    fn assert_rejected(templates: &PageTemplates, expected_reason: &str) {
+        match templates.validate() {
+            Err(EpubWriterError::PageTemplateError { name, reason }) => {
+                assert_eq!(name, "regular");
+                assert!(reason.contains(expected_reason), "{}", reason);
+            }
+            Err(d) => panic!("unexpected error {}", d),
+            Ok(()) => panic!("template accepted"),
+        }
+    }
+
+    #[test]
+    fn fill_template_replaces_placeholders() {
+        let output = fill_template(
+            "<p a=\"{{width}}\">{{title}} {{title}}</p>",
+            &[("width", "10"), ("title", "T")],
+        );
+        assert_eq!(output, "<p a=\"10\">T T</p>");
+    }
+
+    #[test]
+    fn fill_template_does_not_expand_values() {
+        let output = fill_template(
+            "{{title}}{{image}}",
+            &[("title", "{{image}}"), ("image", "a.png")],
+        );
+        assert_eq!(output, "{{image}}a.png");
+    }
+
+    #[test]
+    fn fill_template_keeps_unknown_and_unterminated_placeholders() {
+        assert_eq!(
+            fill_template("{{other}} {{title", &[("title", "T")]),
+            "{{other}} {{title"
+        );
+    }
+
+    #[test]
+    fn default_templates_are_valid() {
+        PageTemplates::default().validate().unwrap();
+        with_regular(REGULAR).validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_unknown_placeholders() {
+        assert_rejected(
+            &with_regular(&REGULAR.replace("{{title}}", "{{chapter}}")),
+            "unknown placeholder {{chapter}}",
+        );
+    }
+
+    #[test]
+    fn validate_rejects_unterminated_placeholders() {
+        assert_rejected(
+            &with_regular(&REGULAR.replace("{{title}}", "{{title")),
+            "unterminated placeholder",
+        );
+        assert_rejected(
+            &with_regular(&REGULAR.replace("{{title}}", "{{title}} {{")),
+            "unterminated placeholder",
+        );
+    }
+
+    #[test]
+    fn validate_rejects_malformed_documents() {
+        for d in [
+            REGULAR.replace("</body>", ""),
+            REGULAR.replace("<head>", "<head"),
+            REGULAR.replace("{{title}}", "{{title}} & more"),
+            "not xml".to_string(),
+        ] {
+            assert_rejected(&with_regular(&d), "");
+        }
+    }
+}
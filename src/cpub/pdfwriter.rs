@@ -0,0 +1,609 @@
+use super::bookwriter::BookWriter;
+use super::countingwriter::CountingWriter;
+use super::errors::EpubWriterError;
+use super::metadata::Metadata;
+use super::observer::{EpubWriterEvent, EpubWriterObserver, EventDispatcher, FinalizePhase};
+use super::pageoptions::PageOptions;
+use super::pagesequence::PageSequence;
+use super::preparedimage::PreparedImage;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{ColorType, DynamicImage, ImageFormat};
+use std::io::prelude::*;
+
+const CATALOG_ID: u32 = 1;
+const PAGE_TREE_ID: u32 = 2;
+
+/// Writes books as PDF documents, with every page sized to its image.
+///
+/// JPEG images are embedded as they are, other formats are decoded and stored losslessly.
+/// Spreads are rendered either as a single wide page or split in two halves, in which case
+/// a blank page follows the cover when needed to keep halves facing in two page view.
+pub struct PdfWriter<W: Write> {
+    metadata: Metadata,
+    split_spreads: bool,
+    pages: PageSequence,
+    object_offsets: Vec<u64>,
+    offset: u64,
+    cover_page_ids: Vec<u32>,
+    image_page_ids: Vec<Vec<u32>>,
+    events: EventDispatcher,
+    finalized: bool,
+    inner: CountingWriter<W>,
+}
+
+impl<W: Write> PdfWriter<W> {
+    pub fn new(
+        inner: W,
+        metadata: Metadata,
+        split_spreads: bool,
+    ) -> Result<PdfWriter<W>, EpubWriterError> {
+        metadata.validate()?;
+
+        let events = EventDispatcher::new();
+        let inner = CountingWriter::new(inner, events.bytes_written());
+        let mut output = PdfWriter {
+            metadata,
+            split_spreads,
+            pages: PageSequence::new(),
+            // Catalog and page tree ids are reserved, both objects are written last
+            object_offsets: vec![0, 0],
+            offset: 0,
+            cover_page_ids: Vec::default(),
+            image_page_ids: Vec::default(),
+            events,
+            finalized: false,
+            inner,
+        };
+
+        output.write_bytes(b"%PDF-1.7\n%\xE2\xE3\xCF\xD3\n")?;
+        return Ok(output);
+    }
+
+    pub fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        self.events.set_observer(observer);
+    }
+
+    pub fn set_cover<T: std::io::Read>(&mut self, image: &mut T) -> Result<(), EpubWriterError> {
+        return self.set_prepared_cover(PreparedImage::read(image)?);
+    }
+
+    pub fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let page_image = self.pages.set_cover(page_image)?;
+        let (size, color_type) = (page_image.size, page_image.color_type);
+        let img_filename = page_image.image_file_name();
+        let pixels = match page_image.format {
+            ImageFormat::Jpeg => None,
+            _ => Some(page_image.decode(&buffer)?),
+        };
+
+        self.cover_page_ids = self.add_image_pages(&buffer, pixels, color_type, size, false)?;
+
+        self.events.notify(EpubWriterEvent::CoverSet {
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    pub fn add_image<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        label: Option<String>,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_image_with_options(
+            image,
+            PageOptions {
+                nav_label: label,
+                ..Default::default()
+            },
+        );
+    }
+
+    pub fn add_image_with_options<T: std::io::Read>(
+        &mut self,
+        image: &mut T,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return self.add_prepared_image(PreparedImage::read(image)?, options);
+    }
+
+    pub fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Err(EpubWriterError::FinalizedError());
+        }
+
+        let (buffer, page_image) = image.into_parts();
+        let page_image = self.pages.add_image(page_image, options)?;
+        let (size, color_type, spread) =
+            (page_image.size, page_image.color_type, page_image.spread);
+        let img_filename = page_image.image_file_name();
+        let pixels = match page_image.format {
+            ImageFormat::Jpeg => None,
+            _ => Some(page_image.decode(&buffer)?),
+        };
+        let index = self.pages.images.len() - 1;
+        if spread {
+            self.events
+                .notify(EpubWriterEvent::SpreadDetected { index, size });
+        }
+
+        let page_ids = self.add_image_pages(&buffer, pixels, color_type, size, spread)?;
+        self.image_page_ids.push(page_ids);
+
+        self.events.notify(EpubWriterEvent::PageAdded {
+            index,
+            file_name: &img_filename,
+        });
+        self.events.notify_bytes_written();
+        return Ok(());
+    }
+
+    pub fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        if self.finalized {
+            return Ok(());
+        }
+
+        self.finalized = true;
+        self.pages.validate()?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Started));
+
+        let mut page_ids = self.cover_page_ids.clone();
+        if self.split_spreads && self.pages.cover_spacer_required {
+            let size = self.pages.cover.as_ref().unwrap().size;
+            let content_id = self.add_stream_object("", b"")?;
+            page_ids.push(self.add_page_object(size, content_id, None)?);
+            self.events.notify(EpubWriterEvent::SpacerInserted);
+        }
+        for i in self.image_page_ids.iter() {
+            page_ids.extend(i.iter());
+        }
+
+        let outlines_id = self.add_outlines()?;
+        self.events.notify(EpubWriterEvent::Finalizing(
+            FinalizePhase::NavigationWritten,
+        ));
+        let info_id = self.add_info()?;
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::MetadataWritten));
+
+        let kids = page_ids
+            .iter()
+            .map(|d| format!("{} 0 R", d))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.write_object(
+            PAGE_TREE_ID,
+            &format!(
+                "<< /Type /Pages /Kids [{}] /Count {} >>",
+                kids,
+                page_ids.len()
+            ),
+        )?;
+
+        let mut catalog = format!(
+            "<< /Type /Catalog /Pages {} 0 R /Lang {}",
+            PAGE_TREE_ID,
+            pdf_text_string(&self.metadata.language)
+        );
+        if let Some(d) = outlines_id {
+            catalog.push_str(&format!(" /Outlines {} 0 R /PageMode /UseOutlines", d));
+        }
+        if self.split_spreads {
+            catalog.push_str(" /PageLayout /TwoPageRight");
+        }
+        if self.metadata.right_to_left {
+            catalog.push_str(" /ViewerPreferences << /Direction /R2L >>");
+        }
+        catalog.push_str(" >>");
+        self.write_object(CATALOG_ID, &catalog)?;
+
+        let xref_offset = self.offset;
+        let mut xref = format!(
+            "xref\n0 {}\n0000000000 65535 f \n",
+            self.object_offsets.len() + 1
+        );
+        for i in self.object_offsets.iter() {
+            xref.push_str(&format!("{:010} 00000 n \n", i));
+        }
+        xref.push_str(&format!(
+            "trailer\n<< /Size {} /Root {} 0 R /Info {} 0 R >>\nstartxref\n{}\n%%EOF\n",
+            self.object_offsets.len() + 1,
+            CATALOG_ID,
+            info_id,
+            xref_offset
+        ));
+        self.write_bytes(xref.as_bytes())?;
+        self.inner.flush()?;
+
+        self.events.notify_bytes_written();
+        self.events
+            .notify(EpubWriterEvent::Finalizing(FinalizePhase::Completed));
+        return Ok(());
+    }
+
+    fn add_image_pages(
+        &mut self,
+        image_data: &[u8],
+        pixels: Option<DynamicImage>,
+        color_type: ColorType,
+        size: (u32, u32),
+        spread: bool,
+    ) -> Result<Vec<u32>, EpubWriterError> {
+        let image_id = self.add_image_object(image_data, pixels, color_type, size)?;
+
+        if !(spread && self.split_spreads) {
+            let content = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", size.0, size.1);
+            let content_id = self.add_stream_object("", content.as_bytes())?;
+            return Ok(vec![self.add_page_object(
+                size,
+                content_id,
+                Some(image_id),
+            )?]);
+        }
+
+        let half_width = size.0 / 2;
+        let mut output = Vec::<u32>::new();
+        for x_offset in [0, -(half_width as i64)] {
+            let content = format!("q {} 0 0 {} {} 0 cm /Im0 Do Q", size.0, size.1, x_offset);
+            let content_id = self.add_stream_object("", content.as_bytes())?;
+            output.push(self.add_page_object((half_width, size.1), content_id, Some(image_id))?);
+        }
+        if self.metadata.right_to_left {
+            output.reverse();
+        }
+
+        return Ok(output);
+    }
+
+    /// Embeds JPEG data as is when no decoded pixels are passed,
+    /// otherwise stores the pixels losslessly.
+    fn add_image_object(
+        &mut self,
+        image_data: &[u8],
+        pixels: Option<DynamicImage>,
+        color_type: ColorType,
+        size: (u32, u32),
+    ) -> Result<u32, EpubWriterError> {
+        let img = match pixels {
+            Some(d) => d,
+            None => {
+                let (color_space, decode) = match jpeg_component_count(image_data) {
+                    Some((1, _)) => ("/DeviceGray", ""),
+                    // Adobe applications write CMYK JPEGs inverted, marking them with APP14
+                    Some((4, true)) => ("/DeviceCMYK", " /Decode [1 0 1 0 1 0 1 0]"),
+                    Some((4, false)) => ("/DeviceCMYK", ""),
+                    Some((3, _)) => ("/DeviceRGB", ""),
+                    _ => return Err(EpubWriterError::UnsupportedImageError),
+                };
+
+                let dictionary = format!(
+                    "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8{} /Filter /DCTDecode",
+                    size.0, size.1, color_space, decode
+                );
+                return Ok(self.add_stream_object(&dictionary, image_data)?);
+            }
+        };
+
+        let mut soft_mask = String::new();
+        if color_type.has_alpha() {
+            let alpha = img.to_rgba8().pixels().map(|d| d.0[3]).collect::<Vec<_>>();
+            let dictionary = format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /DeviceGray /BitsPerComponent 8 /Filter /FlateDecode",
+                size.0, size.1
+            );
+            let mask_id = self.add_stream_object(&dictionary, &deflate(&alpha)?)?;
+            soft_mask = format!(" /SMask {} 0 R", mask_id);
+        }
+
+        let (color_space, pixels) = match color_type {
+            ColorType::L8 | ColorType::La8 | ColorType::L16 | ColorType::La16 => {
+                ("/DeviceGray", img.to_luma8().into_raw())
+            }
+            _ => ("/DeviceRGB", img.to_rgb8().into_raw()),
+        };
+        let dictionary = format!(
+            "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {} /BitsPerComponent 8 /Filter /FlateDecode{}",
+            size.0, size.1, color_space, soft_mask
+        );
+        return Ok(self.add_stream_object(&dictionary, &deflate(&pixels)?)?);
+    }
+
+    fn add_page_object(
+        &mut self,
+        size: (u32, u32),
+        content_id: u32,
+        image_id: Option<u32>,
+    ) -> Result<u32, EpubWriterError> {
+        let resources = match image_id {
+            Some(d) => format!("<< /XObject << /Im0 {} 0 R >> >>", d),
+            None => "<< >>".to_string(),
+        };
+
+        let id = self.reserve_object_id();
+        self.write_object(
+            id,
+            &format!(
+                "<< /Type /Page /Parent {} 0 R /MediaBox [0 0 {} {}] /Resources {} /Contents {} 0 R >>",
+                PAGE_TREE_ID, size.0, size.1, resources, content_id
+            ),
+        )?;
+        return Ok(id);
+    }
+
+    fn add_outlines(&mut self) -> Result<Option<u32>, EpubWriterError> {
+        let bookmarks = self
+            .pages
+            .images
+            .iter()
+            .zip(self.image_page_ids.iter())
+            .filter(|(d, _)| d.nav_label.is_some())
+            .map(|(d, e)| (d.nav_label.clone().unwrap(), e[0]))
+            .collect::<Vec<_>>();
+        if bookmarks.is_empty() {
+            return Ok(None);
+        }
+
+        let outlines_id = self.reserve_object_id();
+        let item_ids = bookmarks
+            .iter()
+            .map(|_| self.reserve_object_id())
+            .collect::<Vec<_>>();
+
+        for (i, (label, page_id)) in bookmarks.iter().enumerate() {
+            let mut item = format!(
+                "<< /Title {} /Parent {} 0 R /Dest [{} 0 R /Fit]",
+                pdf_text_string(label),
+                outlines_id,
+                page_id
+            );
+            if i > 0 {
+                item.push_str(&format!(" /Prev {} 0 R", item_ids[i - 1]));
+            }
+            if let Some(d) = item_ids.get(i + 1) {
+                item.push_str(&format!(" /Next {} 0 R", d));
+            }
+            item.push_str(" >>");
+            self.write_object(item_ids[i], &item)?;
+        }
+
+        self.write_object(
+            outlines_id,
+            &format!(
+                "<< /Type /Outlines /First {} 0 R /Last {} 0 R /Count {} >>",
+                item_ids.first().unwrap(),
+                item_ids.last().unwrap(),
+                item_ids.len()
+            ),
+        )?;
+
+        return Ok(Some(outlines_id));
+    }
+
+    fn add_info(&mut self) -> Result<u32, EpubWriterError> {
+        let mut info = format!(
+            "<< /Title {} /Author {} /Producer {} /CreationDate {} /ModDate {}",
+            pdf_text_string(&self.metadata.title),
+            pdf_text_string(&self.metadata.author),
+            pdf_text_string(&format!("cpub {}", env!("CARGO_PKG_VERSION"))),
+            pdf_text_string(
+                &self
+                    .metadata
+                    .published_date
+                    .format("D:%Y%m%d%H%M%SZ")
+                    .to_string()
+            ),
+            pdf_text_string(&chrono::Utc::now().format("D:%Y%m%d%H%M%SZ").to_string())
+        );
+        if let Some(d) = self.metadata.description.as_ref() {
+            info.push_str(&format!(" /Subject {}", pdf_text_string(d)));
+        }
+        if !self.metadata.tags.is_empty() {
+            let tags = self
+                .metadata
+                .tags
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ");
+            info.push_str(&format!(" /Keywords {}", pdf_text_string(&tags)));
+        }
+        // Fields without a standard entry are stored as custom keys
+        info.push_str(&format!(
+            " /Publisher {}",
+            pdf_text_string(&self.metadata.publisher)
+        ));
+        if let Some(d) = self.metadata.series.as_ref() {
+            info.push_str(&format!(" /Series {}", pdf_text_string(d)));
+        }
+        if let Some(d) = self.metadata.source.as_ref() {
+            info.push_str(&format!(" /Source {}", pdf_text_string(d)));
+        }
+        if let Some(d) = self.metadata.copyright.as_ref() {
+            info.push_str(&format!(" /Copyright {}", pdf_text_string(d)));
+        }
+        info.push_str(" >>");
+
+        let id = self.reserve_object_id();
+        self.write_object(id, &info)?;
+        return Ok(id);
+    }
+
+    fn add_stream_object(&mut self, dictionary: &str, data: &[u8]) -> Result<u32, std::io::Error> {
+        let id = self.reserve_object_id();
+        self.object_offsets[id as usize - 1] = self.offset;
+        self.write_bytes(
+            format!(
+                "{} 0 obj\n<< {} /Length {} >>\nstream\n",
+                id,
+                dictionary,
+                data.len()
+            )
+            .as_bytes(),
+        )?;
+        self.write_bytes(data)?;
+        self.write_bytes(b"\nendstream\nendobj\n")?;
+        return Ok(id);
+    }
+
+    fn reserve_object_id(&mut self) -> u32 {
+        self.object_offsets.push(0);
+        return self.object_offsets.len() as u32;
+    }
+
+    fn write_object(&mut self, id: u32, content: &str) -> Result<(), std::io::Error> {
+        self.object_offsets[id as usize - 1] = self.offset;
+        self.write_bytes(format!("{} 0 obj\n{}\nendobj\n", id, content).as_bytes())?;
+        return Ok(());
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.inner.write_all(data)?;
+        self.offset += data.len() as u64;
+        return Ok(());
+    }
+}
+
+impl<W: Write> BookWriter for PdfWriter<W> {
+    fn set_observer(&mut self, observer: Box<dyn EpubWriterObserver + Send>) {
+        PdfWriter::set_observer(self, observer);
+    }
+
+    fn set_prepared_cover(&mut self, image: PreparedImage) -> Result<(), EpubWriterError> {
+        return PdfWriter::set_prepared_cover(self, image);
+    }
+
+    fn add_prepared_image(
+        &mut self,
+        image: PreparedImage,
+        options: PageOptions,
+    ) -> Result<(), EpubWriterError> {
+        return PdfWriter::add_prepared_image(self, image, options);
+    }
+
+    fn finalize(&mut self) -> Result<(), EpubWriterError> {
+        return PdfWriter::finalize(self);
+    }
+}
+
+/// Encodes text as a literal string when plain ASCII, as UTF-16BE hex string otherwise.
+fn pdf_text_string(value: &str) -> String {
+    if value.bytes().all(|d| (0x20..0x7F).contains(&d)) {
+        let escaped = value
+            .replace('\\', "\\\\")
+            .replace('(', "\\(")
+            .replace(')', "\\)");
+        return format!("({})", escaped);
+    }
+
+    let mut output = String::from("<FEFF");
+    for i in value.encode_utf16() {
+        output.push_str(&format!("{:04X}", i));
+    }
+    output.push('>');
+    return output;
+}
+
+fn deflate(data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    return encoder.finish();
+}
+
+/// Number of color components of a JPEG image, and whether it has an Adobe APP14 segment
+fn jpeg_component_count(data: &[u8]) -> Option<(u8, bool)> {
+    let mut adobe = false;
+    let mut position = 2;
+    while position + 9 < data.len() {
+        if data[position] != 0xFF {
+            return None;
+        }
+
+        let marker = data[position + 1];
+        if marker == 0xFF {
+            position += 1;
+            continue;
+        }
+
+        // Start of frame markers, excluding DHT, JPG and DAC which share the range
+        if (0xC0..=0xCF).contains(&marker) && !matches!(marker, 0xC4 | 0xC8 | 0xCC) {
+            return Some((data[position + 9], adobe));
+        }
+        if marker == 0xEE && &data[position + 4..position + 9] == b"Adobe" {
+            adobe = true;
+        }
+
+        let length = u16::from_be_bytes([data[position + 2], data[position + 3]]) as usize;
+        position += 2 + length;
+    }
+
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Headers of a 1x1 JPEG image with four components, with or without an Adobe segment
+    fn cmyk_jpeg_header(adobe: bool) -> Vec<u8> {
+        let mut output = vec![0xFF, 0xD8];
+        if adobe {
+            output.extend_from_slice(b"\xFF\xEE\x00\x0EAdobe\x00\x64\x00\x00\x00\x00\x02");
+        } else {
+            output
+                .extend_from_slice(b"\xFF\xE0\x00\x10JFIF\x00\x01\x01\x00\x00\x01\x00\x01\x00\x00");
+        }
+        output.extend_from_slice(b"\xFF\xC0\x00\x14\x08\x00\x01\x00\x01\x04");
+        for i in 1..=4u8 {
+            output.extend_from_slice(&[i, 0x11, 0]);
+        }
+        output.extend_from_slice(b"\xFF\xD9");
+        return output;
+    }
+
+    fn image_dictionary(data: &[u8]) -> String {
+        let mut output = Vec::<u8>::new();
+        let mut writer = PdfWriter::new(&mut output, Metadata::default(), false).unwrap();
+        writer
+            .add_image_object(data, None, ColorType::Rgb8, (1, 1))
+            .unwrap();
+        drop(writer);
+
+        let output = String::from_utf8_lossy(&output).into_owned();
+        let start = output.find("<< /Type /XObject").unwrap();
+        return output[start..start + output[start..].find(">>").unwrap()].to_string();
+    }
+
+    #[test]
+    fn jpeg_component_count_finds_adobe_segment() {
+        assert_eq!(
+            jpeg_component_count(&cmyk_jpeg_header(true)),
+            Some((4, true))
+        );
+        assert_eq!(
+            jpeg_component_count(&cmyk_jpeg_header(false)),
+            Some((4, false))
+        );
+    }
+
+    #[test]
+    fn cmyk_jpeg_inverted_only_with_adobe_segment() {
+        let dictionary = image_dictionary(&cmyk_jpeg_header(true));
+        assert!(dictionary.contains("/ColorSpace /DeviceCMYK"));
+        assert!(dictionary.contains("/Decode [1 0 1 0 1 0 1 0]"));
+
+        let dictionary = image_dictionary(&cmyk_jpeg_header(false));
+        assert!(dictionary.contains("/ColorSpace /DeviceCMYK"));
+        assert!(!dictionary.contains("/Decode"));
+    }
+}
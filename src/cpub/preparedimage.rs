@@ -0,0 +1,91 @@
+use super::errors::EpubWriterError;
+use super::pageimage::PageImage;
+use image::codecs::jpeg::JpegEncoder;
+use image::{ImageFormat, ImageOutputFormat};
+use std::io::{Cursor, Read};
+
+/// Quality JPEG images are re-encoded at when cropped
+const JPEG_QUALITY: u8 = 95;
+
+/// Format, size and digest of an image, as found when preparing it
+#[derive(Clone)]
+pub struct ImageProbe {
+    page_image: PageImage,
+    data_len: usize,
+}
+
+/// Image data read and probed ahead of being added to a writer.
+///
+/// Preparing images is independent of writer state, so it can be done on other threads
+/// while the writer is fed prepared images in reading order.
+#[derive(Clone)]
+pub struct PreparedImage {
+    data: Vec<u8>,
+    page_image: PageImage,
+}
+
+impl PreparedImage {
+    pub fn new(data: Vec<u8>) -> Result<PreparedImage, EpubWriterError> {
+        let page_image = PageImage::new(&data, None)?;
+        return Ok(PreparedImage { data, page_image });
+    }
+
+    pub fn read<T: Read>(image: &mut T) -> Result<PreparedImage, EpubWriterError> {
+        let mut buffer: Vec<u8> = Vec::new();
+        image.read_to_end(&mut buffer)?;
+        return PreparedImage::new(buffer);
+    }
+
+    /// Prepares image data probed earlier again without analysing it, e.g. when the file it
+    /// was read from is unchanged. The data is probed anew if its size differs.
+    pub fn with_probe(data: Vec<u8>, probe: &ImageProbe) -> Result<PreparedImage, EpubWriterError> {
+        if data.len() != probe.data_len {
+            return PreparedImage::new(data);
+        }
+
+        let page_image = probe.page_image.clone();
+        return Ok(PreparedImage { data, page_image });
+    }
+
+    pub fn probe(&self) -> ImageProbe {
+        return ImageProbe {
+            page_image: self.page_image.clone(),
+            data_len: self.data.len(),
+        };
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        return self.page_image.size;
+    }
+
+    pub fn is_spread(&self) -> bool {
+        return self.page_image.spread;
+    }
+
+    /// Crops the right or left half of a spread, e.g. to use the front of a wraparound cover
+    /// as the book cover. Unlike everything else in cpub, this re-encodes the image:
+    /// JPEG images at high quality, others losslessly as PNG.
+    pub fn spread_half(&self, right: bool) -> Result<PreparedImage, EpubWriterError> {
+        let image = self.page_image.decode(&self.data)?;
+        let half_width = image.width() / 2;
+        let x = match right {
+            true => image.width() - half_width,
+            false => 0,
+        };
+        let half = image.crop_imm(x, 0, half_width, image.height());
+
+        let mut data = Vec::<u8>::new();
+        match self.page_image.format {
+            ImageFormat::Jpeg => {
+                JpegEncoder::new_with_quality(&mut data, JPEG_QUALITY).encode_image(&half)?
+            }
+            _ => half.write_to(&mut Cursor::new(&mut data), ImageOutputFormat::Png)?,
+        }
+
+        return PreparedImage::new(data);
+    }
+
+    pub(super) fn into_parts(self) -> (Vec<u8>, PageImage) {
+        return (self.data, self.page_image);
+    }
+}
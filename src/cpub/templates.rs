@@ -6,55 +6,75 @@ pub const CONTAINER_XML: &str = r#"<?xml version="1.0" encoding="UTF-8" ?>
 </container>"#;
 
 pub const PAGE_SPACER_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
-<html lang="en-US" xml:lang="en-US" xmlns="http://www.w3.org/1999/xhtml">
+<html lang="{{language}}" xml:lang="{{language}}" xmlns="http://www.w3.org/1999/xhtml">
   <head>
     <meta charset="utf-8" />
-    <meta name="viewport" content="width=IMGW, height=IMGH" />
-    <title>PGTITLE</title>
+    <meta name="viewport" content="width={{width}}, height={{height}}" />
+    <title>{{title}}</title>
   </head>
   <body>
-    <svg width="IMGW" height="IMGH" viewBox="0 0 IMGW IMGH" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve" />
+    <svg width="{{width}}" height="{{height}}" viewBox="0 0 {{width}} {{height}}" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve" />
   </body>
 </html>"#;
 
 pub const PAGE_REGULAR_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
-<html lang="en-US" xml:lang="en-US" xmlns="http://www.w3.org/1999/xhtml">
+<html lang="{{language}}" xml:lang="{{language}}" xmlns="http://www.w3.org/1999/xhtml">
   <head>
     <meta charset="utf-8" />
-    <meta name="viewport" content="width=IMGW, height=IMGH" />
-    <title>PGTITLE</title>
+    <meta name="viewport" content="width={{width}}, height={{height}}" />
+    <title>{{title}}</title>
   </head>
   <body>
-    <svg width="IMGW" height="IMGH" viewBox="0 0 IMGW IMGH" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve">
-        <image x="0" y="0" width="IMGW" height="IMGH" xlink:href="FILENAME"/>
+    <svg width="{{width}}" height="{{height}}" viewBox="0 0 {{width}} {{height}}" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve"{{aria_label}}>{{svg_title}}
+        <image x="0" y="0" width="{{width}}" height="{{height}}" xlink:href="{{image}}"/>
     </svg>
   </body>
 </html>"#;
 
 pub const PAGE_SPREAD_R_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
-<html lang="en-US" xml:lang="en-US" xmlns="http://www.w3.org/1999/xhtml">
+<html lang="{{language}}" xml:lang="{{language}}" xmlns="http://www.w3.org/1999/xhtml">
   <head>
     <meta charset="utf-8" />
-    <meta name="viewport" content="width=IMGHW, height=IMGH" />
-    <title>PGTITLE</title>
+    <meta name="viewport" content="width={{half_width}}, height={{height}}" />
+    <title>{{title}}</title>
   </head>
   <body>
-    <svg width="IMGHW" height="IMGH" viewBox="0 0 IMGHW IMGH" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve">
-        <image x="-IMGHW" y="0" width="IMGW" height="IMGH" xlink:href="FILENAME"/>
+    <svg width="{{half_width}}" height="{{height}}" viewBox="0 0 {{half_width}} {{height}}" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve"{{aria_label}}>{{svg_title}}
+        <image x="-{{half_width}}" y="0" width="{{width}}" height="{{height}}" xlink:href="{{image}}"/>
     </svg>
   </body>
 </html>"#;
 
 pub const PAGE_SPREAD_L_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
-<html lang="en-US" xml:lang="en-US" xmlns="http://www.w3.org/1999/xhtml">
+<html lang="{{language}}" xml:lang="{{language}}" xmlns="http://www.w3.org/1999/xhtml">
   <head>
     <meta charset="utf-8" />
-    <meta name="viewport" content="width=IMGHW, height=IMGH" />
-    <title>PGTITLE</title>
+    <meta name="viewport" content="width={{half_width}}, height={{height}}" />
+    <title>{{title}}</title>
   </head>
   <body>
-    <svg width="IMGHW" height="IMGH" viewBox="0 0 IMGHW IMGH" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve">
-        <image x="0" y="0" width="IMGW" height="IMGH" xlink:href="FILENAME"/>
+    <svg width="{{half_width}}" height="{{height}}" viewBox="0 0 {{half_width}} {{height}}" version="1.1" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xml:space="preserve"{{aria_label}}>{{svg_title}}
+        <image x="0" y="0" width="{{width}}" height="{{height}}" xlink:href="{{image}}"/>
     </svg>
   </body>
 </html>"#;
+
+pub const PAGE_TEXT_XML: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html lang="{{language}}" xml:lang="{{language}}" xmlns="http://www.w3.org/1999/xhtml">
+  <head>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width={{width}}, height={{height}}" />
+    <title>{{title}}</title>
+    <style>
+      body { margin: 0; width: {{width}}px; height: {{height}}px; overflow: hidden; }
+      div.text { box-sizing: border-box; width: 100%; height: 100%; padding: 8%; font-size: {{font_size}}px; line-height: 1.4; }
+      div.text img { display: block; max-width: 100%; max-height: 40%; margin: 0 auto 1em auto; }
+      div.text h1 { font-size: 1.6em; text-align: center; margin: 0 0 1em 0; }
+      div.text p { text-align: justify; margin: 0 0 0.8em 0; }
+    </style>
+  </head>
+  <body>
+    <div class="text">
+{{text_image}}{{text_content}}    </div>
+  </body>
+</html>"#;
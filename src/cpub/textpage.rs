@@ -0,0 +1,28 @@
+use super::preparedimage::PreparedImage;
+use xml::escape::escape_str_pcdata;
+
+/// Page made of text rather than a scanned image, like credits or translator notes.
+///
+/// Rendered as a fixed layout page matching the size of the page before it,
+/// with the image, if any, shown above the title and paragraphs.
+#[derive(Default)]
+pub struct TextPage {
+    pub title: Option<String>,
+    pub paragraphs: Vec<String>,
+    pub image: Option<PreparedImage>,
+}
+
+impl TextPage {
+    /// Title and paragraphs as escaped XHTML elements
+    pub(super) fn render_body(&self) -> String {
+        let mut output = String::new();
+        if let Some(title) = self.title.as_ref() {
+            output.push_str(&format!("      <h1>{}</h1>\n", escape_str_pcdata(title)));
+        }
+        for i in self.paragraphs.iter() {
+            output.push_str(&format!("      <p>{}</p>\n", escape_str_pcdata(i)));
+        }
+
+        return output;
+    }
+}
@@ -0,0 +1,62 @@
+use std::io::{Error, ErrorKind, Result, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex};
+
+/// Records writes along with the offsets they were made at instead of performing them.
+///
+/// Lets synchronous writers produce output for a sink they cannot write to directly:
+/// pending writes are taken after each operation and replayed onto the actual sink.
+/// The log is shared, so it can be drained while the writer is owned by something else.
+#[derive(Clone, Default)]
+pub struct WriteLog {
+    state: Arc<Mutex<WriteLogState>>,
+}
+
+#[derive(Default)]
+struct WriteLogState {
+    position: u64,
+    len: u64,
+    pending: Vec<(u64, Vec<u8>)>,
+}
+
+impl WriteLog {
+    /// Takes writes recorded since the last call as (offset, data) pairs, in the order they were made
+    pub fn take_pending(&self) -> Vec<(u64, Vec<u8>)> {
+        return std::mem::take(&mut self.state.lock().unwrap().pending);
+    }
+}
+
+impl Write for WriteLog {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let position = state.position;
+        match state.pending.last_mut() {
+            Some((offset, data)) if *offset + data.len() as u64 == position => {
+                data.extend_from_slice(buf)
+            }
+            _ => state.pending.push((position, buf.to_vec())),
+        }
+
+        state.position += buf.len() as u64;
+        state.len = state.len.max(state.position);
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl Seek for WriteLog {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let mut state = self.state.lock().unwrap();
+        let position = match pos {
+            SeekFrom::Start(d) => Some(d),
+            SeekFrom::End(d) => state.len.checked_add_signed(d),
+            SeekFrom::Current(d) => state.position.checked_add_signed(d),
+        };
+
+        state.position = position
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Seek to a negative position"))?;
+        return Ok(state.position);
+    }
+}
@@ -0,0 +1,101 @@
+use super::countingwriter::CountingWriter;
+use std::io::{Error, Read, Result, Write};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use zip::read::ZipFile;
+use zip::result::ZipResult;
+use zip::write::{SimpleFileOptions, StreamWriter, ZipFileBuilder};
+use zip::{CompressionMethod, ZipWriter};
+
+/// Zip archive being written, either to a seekable output or streamed.
+///
+/// Seekable outputs get sizes and checksums written in local headers once each entry is complete,
+/// streamed ones get them in data descriptors following entry data, so no seeking is needed.
+pub enum ZipOutput<W: Write> {
+    Seekable(ZipWriter<CountingWriter<W>>),
+    Streaming(ZipWriter<StreamWriter<CountingWriter<W>>>),
+    Finished,
+}
+
+impl<W: Write> ZipOutput<W> {
+    pub fn new_seekable(inner: W, bytes_written: Arc<AtomicU64>) -> ZipOutput<W>
+    where
+        W: std::io::Seek,
+    {
+        let inner = CountingWriter::new_seekable(inner, bytes_written);
+        return ZipOutput::Seekable(ZipWriter::new(inner));
+    }
+
+    pub fn new_streaming(inner: W, bytes_written: Arc<AtomicU64>) -> ZipOutput<W> {
+        let inner = CountingWriter::new(inner, bytes_written);
+        return ZipOutput::Streaming(ZipWriter::new_stream(inner));
+    }
+
+    pub fn start_file(&mut self, name: &str, options: SimpleFileOptions) -> ZipResult<()> {
+        match self {
+            ZipOutput::Seekable(d) => d.start_file(name, options),
+            ZipOutput::Streaming(d) => d.start_file(name, options),
+            ZipOutput::Finished => Err(ZipOutput::<W>::finished_error().into()),
+        }
+    }
+
+    /// Adds an uncompressed entry with its size and checksum in the local header, even when
+    /// streaming, without any extra field
+    pub fn add_stored_file(&mut self, name: &str, data: &[u8]) -> ZipResult<()> {
+        // Streamed entries only get a data descriptor when they carry file attributes
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .external_attributes(0);
+        let mut file = ZipFileBuilder::new(name, options)?;
+        file.write_all(data)?;
+        let file = file.finish()?;
+
+        match self {
+            ZipOutput::Seekable(d) => d.add_prepared_file(file),
+            ZipOutput::Streaming(d) => d.add_prepared_file(file),
+            ZipOutput::Finished => Err(ZipOutput::<W>::finished_error().into()),
+        }
+    }
+
+    /// Copies an entry of another archive without decompressing and compressing it again
+    pub fn raw_copy_file<R: Read>(&mut self, file: ZipFile<'_, R>) -> ZipResult<()> {
+        match self {
+            ZipOutput::Seekable(d) => d.raw_copy_file(file),
+            ZipOutput::Streaming(d) => d.raw_copy_file(file),
+            ZipOutput::Finished => Err(ZipOutput::<W>::finished_error().into()),
+        }
+    }
+
+    /// Writes the central directory, no more entries can be added afterwards
+    pub fn finish(&mut self) -> ZipResult<()> {
+        match std::mem::replace(self, ZipOutput::Finished) {
+            ZipOutput::Seekable(d) => d.finish()?.flush()?,
+            ZipOutput::Streaming(d) => d.finish()?.flush()?,
+            ZipOutput::Finished => return Err(ZipOutput::<W>::finished_error().into()),
+        }
+
+        return Ok(());
+    }
+
+    fn finished_error() -> Error {
+        return Error::other("Zip archive already finished");
+    }
+}
+
+impl<W: Write> Write for ZipOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            ZipOutput::Seekable(d) => d.write(buf),
+            ZipOutput::Streaming(d) => d.write(buf),
+            ZipOutput::Finished => Err(ZipOutput::<W>::finished_error()),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            ZipOutput::Seekable(d) => d.flush(),
+            ZipOutput::Streaming(d) => d.flush(),
+            ZipOutput::Finished => Ok(()),
+        }
+    }
+}
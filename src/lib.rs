@@ -1,4 +1,24 @@
+#![allow(clippy::needless_return, clippy::enum_variant_names)]
+
 mod cpub;
 
+pub use cpub::AppleDisplayOptions;
+#[cfg(feature = "async")]
+pub use cpub::AsyncEpubWriter;
+pub use cpub::Azw3Writer;
+pub use cpub::BookWriter;
+pub use cpub::CbzWriter;
+pub use cpub::CompressionPolicy;
 pub use cpub::EpubWriter;
+pub use cpub::EpubWriterEvent;
+pub use cpub::EpubWriterObserver;
+pub use cpub::EpubWriterOptions;
+pub use cpub::FinalizePhase;
+pub use cpub::ImageProbe;
 pub use cpub::Metadata;
+pub use cpub::OrientationLock;
+pub use cpub::PageOptions;
+pub use cpub::PageTemplates;
+pub use cpub::PdfWriter;
+pub use cpub::PreparedImage;
+pub use cpub::TextPage;
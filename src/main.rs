@@ -1,13 +1,26 @@
+#![allow(clippy::needless_return, clippy::too_many_arguments)]
+
+mod watcher;
+
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Duration, SystemTime};
 
 use anyhow::{anyhow, Context, Ok, Result};
 use chrono::{DateTime, Utc};
 use clap::{crate_authors, crate_version, Arg, ArgMatches, Command};
-use cpub::{EpubWriter, Metadata};
+use cpub::{
+    AppleDisplayOptions, Azw3Writer, BookWriter, CbzWriter, CompressionPolicy, EpubWriter,
+    EpubWriterEvent, EpubWriterObserver, EpubWriterOptions, FinalizePhase, ImageProbe, Metadata,
+    OrientationLock, PageOptions, PageTemplates, PdfWriter, PreparedImage,
+};
 
 const CMD_ID_BATCH: &str = "batch";
+const CMD_ID_WATCH: &str = "watch";
 
 const ARG_ID_TITLE: &str = "title";
 const ARG_ID_AUTHOR: &str = "author";
@@ -19,14 +32,161 @@ const ARG_ID_SOURCE: &str = "source";
 const ARG_ID_COPYRIGHT: &str = "copyright";
 const ARG_ID_RTL: &str = "right-to-left";
 const ARG_ID_TAGS: &str = "tags";
+const ARG_ID_ACCESS_MODES: &str = "access-modes";
+const ARG_ID_ACCESS_MODE_SUFFICIENT: &str = "access-mode-sufficient";
+const ARG_ID_ACCESSIBILITY_FEATURES: &str = "accessibility-features";
+const ARG_ID_ACCESSIBILITY_HAZARDS: &str = "accessibility-hazards";
+const ARG_ID_ACCESSIBILITY_SUMMARY: &str = "accessibility-summary";
+const ARG_ID_FORMAT: &str = "format";
+const ARG_ID_SPLIT_SPREADS: &str = "split-spreads";
+const ARG_ID_EPUB2_COMPATIBILITY: &str = "epub2-compat";
+const ARG_ID_APPLE_DISPLAY_OPTIONS: &str = "apple-display-options";
+const ARG_ID_ORIENTATION_LOCK: &str = "orientation-lock";
+const ARG_ID_OPEN_TO_SPREAD: &str = "open-to-spread";
+const ARG_ID_PAGE_NUMBERS_FROM_FILE_NAMES: &str = "page-numbers-from-file-names";
+const ARG_ID_BYTE_ORDER_SORT: &str = "byte-order-sort";
+const ARG_ID_COVER_FILE: &str = "cover-file";
+const ARG_ID_COVER_INDEX: &str = "cover-index";
+const ARG_ID_COVER_NAMED: &str = "cover-named";
+const ARG_ID_COVER_FROM_SPREAD: &str = "cover-from-spread";
+const ARG_ID_COVER_AS_PAGE: &str = "cover-as-page";
+const ARG_ID_BACK_COVER: &str = "back-cover";
+const ARG_ID_APPEND: &str = "append";
+const ARG_ID_APPEND_CHAPTER: &str = "append-chapter";
+const ARG_ID_INCLUDE: &str = "include";
+const ARG_ID_EXCLUDE: &str = "exclude";
+const ARG_ID_MAX_DEPTH: &str = "max-depth";
+const ARG_ID_BACK_MATTER_PAGES: &str = "back-matter-pages";
+const ARG_ID_READ_AHEAD: &str = "read-ahead";
+const ARG_ID_COMPRESSION: &str = "compression";
+const ARG_ID_COMPRESSION_LEVEL: &str = "compression-level";
+const ARG_ID_NO_ZIP64: &str = "no-zip64";
+const ARG_ID_PAGE_TEMPLATES: &str = "page-templates";
 const ARG_ID_BATCH_VOLUME_START_NUMBER: &str = "vsn";
 const ARG_ID_BATCH_VOLUME_NUM_DIGITS: &str = "vnd";
+const ARG_ID_BATCH_JOBS: &str = "jobs";
+const ARG_ID_WATCH_BATCH: &str = "batch";
+const ARG_ID_WATCH_SETTLE: &str = "settle";
 
 const ARG_ID_INPUT: &str = "input";
 const ARG_ID_OUTPUT: &str = "output";
 
 const VOLUME_NUMBER_PLACEHOLDER: &str = "%num%";
 
+const DEFAULT_READ_AHEAD: usize = 8;
+const DEFAULT_WATCH_SETTLE_MS: u64 = 1000;
+
+const FORMAT_EPUB: &str = "epub";
+const FORMAT_AZW3: &str = "azw3";
+const FORMAT_CBZ: &str = "cbz";
+const FORMAT_PDF: &str = "pdf";
+/// Extension of books being written, renamed once complete
+const TEMP_EXTENSION: &str = "epubgen";
+
+const OUTPUT_STDOUT: &str = "-";
+
+const ORIENTATION_LOCK_NONE: &str = "none";
+const ORIENTATION_LOCK_PORTRAIT: &str = "portrait";
+const ORIENTATION_LOCK_LANDSCAPE: &str = "landscape";
+
+const COMPRESSION_STORE_IMAGES: &str = "store-images";
+const COMPRESSION_DEFLATE_ALL: &str = "deflate-all";
+const COMPRESSION_STORE_ALL: &str = "store-all";
+
+fn book_writer_from_args<W: Write + Seek + 'static>(
+    args: &ArgMatches,
+    inner: W,
+    metadata: Metadata,
+) -> Result<Box<dyn BookWriter>> {
+    match args.value_of(ARG_ID_FORMAT).unwrap_or(FORMAT_EPUB) {
+        FORMAT_AZW3 | FORMAT_PDF => streaming_book_writer_from_args(args, inner, metadata),
+        FORMAT_CBZ => Ok(Box::new(CbzWriter::new(inner, metadata)?)),
+        _ if args.is_present(ARG_ID_APPEND) => {
+            let existing_path = args.value_of(ARG_ID_APPEND).unwrap();
+            let existing = File::open(existing_path)
+                .with_context(|| format!("Unable to open {}", existing_path))?;
+            Ok(Box::new(EpubWriter::new_appending(
+                BufReader::new(existing),
+                inner,
+                metadata,
+                epub_writer_options_from_args(args)?,
+            )?))
+        }
+        _ => Ok(Box::new(EpubWriter::new_with_options(
+            inner,
+            metadata,
+            epub_writer_options_from_args(args)?,
+        )?)),
+    }
+}
+
+/// Creates writers for outputs that cannot seek. Zip based formats store entry sizes
+/// after entry data in this case, the others are always written sequentially.
+fn streaming_book_writer_from_args<W: Write + 'static>(
+    args: &ArgMatches,
+    inner: W,
+    metadata: Metadata,
+) -> Result<Box<dyn BookWriter>> {
+    match args.value_of(ARG_ID_FORMAT).unwrap_or(FORMAT_EPUB) {
+        FORMAT_AZW3 => Ok(Box::new(Azw3Writer::new(inner, metadata)?)),
+        FORMAT_CBZ => Ok(Box::new(CbzWriter::new_streaming(inner, metadata)?)),
+        FORMAT_PDF => Ok(Box::new(PdfWriter::new(
+            inner,
+            metadata,
+            args.is_present(ARG_ID_SPLIT_SPREADS),
+        )?)),
+        _ => Ok(Box::new(EpubWriter::new_streaming(
+            inner,
+            metadata,
+            epub_writer_options_from_args(args)?,
+        )?)),
+    }
+}
+
+fn epub_writer_options_from_args(args: &ArgMatches) -> Result<EpubWriterOptions> {
+    let mut apple_display_options = None;
+    if args.is_present(ARG_ID_APPLE_DISPLAY_OPTIONS)
+        || args.is_present(ARG_ID_ORIENTATION_LOCK)
+        || args.is_present(ARG_ID_OPEN_TO_SPREAD)
+    {
+        apple_display_options = Some(AppleDisplayOptions {
+            orientation_lock: match args.value_of(ARG_ID_ORIENTATION_LOCK) {
+                Some(ORIENTATION_LOCK_PORTRAIT) => OrientationLock::PortraitOnly,
+                Some(ORIENTATION_LOCK_LANDSCAPE) => OrientationLock::LandscapeOnly,
+                _ => OrientationLock::None,
+            },
+            open_to_spread: args.is_present(ARG_ID_OPEN_TO_SPREAD),
+        });
+    }
+
+    let mut compression_level = None;
+    if let Some(cl_str) = args.value_of(ARG_ID_COMPRESSION_LEVEL) {
+        match atoi::atoi::<u32>(cl_str.as_bytes()) {
+            Some(cl_u32) if cl_u32 <= 9 => compression_level = Some(cl_u32),
+            _ => eprintln!("Unable to parse compression level. Using default"),
+        }
+    }
+
+    let mut page_templates = PageTemplates::default();
+    if let Some(d) = args.value_of(ARG_ID_PAGE_TEMPLATES) {
+        page_templates = PageTemplates::from_dir(Path::new(d))
+            .with_context(|| format!("Unable to load page templates from {}", d))?;
+    }
+
+    return Ok(EpubWriterOptions {
+        epub2_compatibility: args.is_present(ARG_ID_EPUB2_COMPATIBILITY),
+        apple_display_options,
+        compression_policy: match args.value_of(ARG_ID_COMPRESSION) {
+            Some(COMPRESSION_DEFLATE_ALL) => CompressionPolicy::DeflateAll,
+            Some(COMPRESSION_STORE_ALL) => CompressionPolicy::StoreAll,
+            _ => CompressionPolicy::StoreImages,
+        },
+        compression_level,
+        disable_zip64: args.is_present(ARG_ID_NO_ZIP64),
+        page_templates,
+    });
+}
+
 fn main() {
     fn arg_from_id<'a>(
         arg_id: &'a str,
@@ -67,31 +227,34 @@ fn main() {
             Some('t'),
             "TITLE",
             "Set the title. Occurrences of '%num%' will be replaced by the volume number in batch mode",
-            true,
+            false,
             false,
             true,
             false,
-        ),
+        )
+        .required_unless_present(ARG_ID_APPEND),
         arg_from_id(
             ARG_ID_AUTHOR,
             Some('a'),
             "AUTHOR",
             "Set the author",
-            true,
+            false,
             false,
             true,
             false,
-        ),
+        )
+        .required_unless_present(ARG_ID_APPEND),
         arg_from_id(
             ARG_ID_PUBLISHER,
             Some('p'),
             "PUBLISHER",
             "Set the publisher",
-            true,
+            false,
             false,
             true,
             false,
-        ),
+        )
+        .required_unless_present(ARG_ID_APPEND),
         arg_from_id(
             ARG_ID_PUBLISHED_DATE,
             Some('d'),
@@ -162,6 +325,328 @@ fn main() {
             true,
             true,
         ),
+        arg_from_id(
+            ARG_ID_ACCESS_MODES,
+            None,
+            "ACCESS-MODES",
+            "Set the access modes needed to read the book, e.g. visual",
+            false,
+            false,
+            true,
+            true,
+        ),
+        arg_from_id(
+            ARG_ID_ACCESS_MODE_SUFFICIENT,
+            None,
+            "ACCESS-MODE-SUFFICIENT",
+            "Add a set of access modes sufficient to read the book, comma separated, e.g. textual,visual. Can be repeated",
+            false,
+            false,
+            true,
+            false,
+        )
+        .multiple_occurrences(true),
+        arg_from_id(
+            ARG_ID_ACCESSIBILITY_FEATURES,
+            None,
+            "ACCESSIBILITY-FEATURES",
+            "Set the accessibility features, e.g. alternativeText,printPageNumbers",
+            false,
+            false,
+            true,
+            true,
+        ),
+        arg_from_id(
+            ARG_ID_ACCESSIBILITY_HAZARDS,
+            None,
+            "ACCESSIBILITY-HAZARDS",
+            "Set the accessibility hazards, e.g. none",
+            false,
+            false,
+            true,
+            true,
+        ),
+        arg_from_id(
+            ARG_ID_ACCESSIBILITY_SUMMARY,
+            None,
+            "ACCESSIBILITY-SUMMARY",
+            "Set the accessibility summary",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_FORMAT,
+            Some('f'),
+            "FORMAT",
+            "Set the output format: epub (default), azw3 (Kindle), cbz or pdf",
+            false,
+            false,
+            true,
+            false,
+        )
+        .possible_values([FORMAT_EPUB, FORMAT_AZW3, FORMAT_CBZ, FORMAT_PDF]),
+        arg_from_id(
+            ARG_ID_SPLIT_SPREADS,
+            None,
+            "SPLIT-SPREADS",
+            "Split spreads into two pages instead of a single wide one (pdf only)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_EPUB2_COMPATIBILITY,
+            None,
+            "EPUB2-COMPAT",
+            "Also write EPUB 2 navigation data (toc.ncx, guide and cover meta) for older readers (epub only)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_APPLE_DISPLAY_OPTIONS,
+            None,
+            "APPLE-DISPLAY-OPTIONS",
+            "Write the Apple Books display options file marking the book as fixed layout (epub only)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_ORIENTATION_LOCK,
+            None,
+            "ORIENTATION-LOCK",
+            "Lock the orientation in Apple Books: none (default), portrait or landscape (epub only)",
+            false,
+            false,
+            true,
+            false,
+        )
+        .possible_values([
+            ORIENTATION_LOCK_NONE,
+            ORIENTATION_LOCK_PORTRAIT,
+            ORIENTATION_LOCK_LANDSCAPE,
+        ]),
+        arg_from_id(
+            ARG_ID_OPEN_TO_SPREAD,
+            None,
+            "OPEN-TO-SPREAD",
+            "Open the book to a two page spread in Apple Books (epub only)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_PAGE_NUMBERS_FROM_FILE_NAMES,
+            None,
+            "PAGE-NUMBERS-FROM-FILE-NAMES",
+            "Use the number at the end of each image file name as its printed page number for page navigation",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_BYTE_ORDER_SORT,
+            None,
+            "BYTE-ORDER-SORT",
+            "Sort image files and volume directories by byte value instead of naturally (page2 before page10)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_COVER_FILE,
+            None,
+            "COVER-FILE",
+            "Use an image file outside the input directory as cover instead of the first image",
+            false,
+            false,
+            true,
+            false,
+        )
+        .conflicts_with_all(&[ARG_ID_COVER_INDEX, ARG_ID_COVER_NAMED]),
+        arg_from_id(
+            ARG_ID_COVER_INDEX,
+            None,
+            "COVER-INDEX",
+            "Use the image at this position, starting from 1, as cover instead of the first image",
+            false,
+            false,
+            true,
+            false,
+        )
+        .conflicts_with(ARG_ID_COVER_NAMED),
+        arg_from_id(
+            ARG_ID_COVER_NAMED,
+            None,
+            "COVER-NAMED",
+            "Use the image named cover (e.g. cover.jpg) as cover instead of the first image",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_COVER_FROM_SPREAD,
+            None,
+            "COVER-FROM-SPREAD",
+            "Crop the right half of a spread used as cover, or the left half when reading right to left",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_COVER_AS_PAGE,
+            None,
+            "COVER-AS-PAGE",
+            "Also add the cover image as a page, in its position among the other images",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_BACK_COVER,
+            None,
+            "BACK-COVER",
+            "Use the last image matching this glob pattern (e.g. back*) as back cover, shown after all pages",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_APPEND,
+            None,
+            "APPEND",
+            "Add images as pages after those of an existing ePub generated by cpub, keeping its cover and metadata not set by other options",
+            false,
+            false,
+            true,
+            false,
+        )
+        .conflicts_with_all(&[
+            ARG_ID_COVER_FILE,
+            ARG_ID_COVER_INDEX,
+            ARG_ID_COVER_NAMED,
+            ARG_ID_COVER_FROM_SPREAD,
+            ARG_ID_COVER_AS_PAGE,
+        ]),
+        arg_from_id(
+            ARG_ID_APPEND_CHAPTER,
+            None,
+            "APPEND-CHAPTER",
+            "Start a new chapter with this table of contents label at the first page appended",
+            false,
+            false,
+            true,
+            false,
+        )
+        .requires(ARG_ID_APPEND),
+        arg_from_id(
+            ARG_ID_INCLUDE,
+            None,
+            "INCLUDE",
+            "Only add images matching these glob patterns, matched against file names or, for patterns containing '/', paths relative to the input directory",
+            false,
+            false,
+            true,
+            true,
+        ),
+        arg_from_id(
+            ARG_ID_EXCLUDE,
+            None,
+            "EXCLUDE",
+            "Skip images and directories matching these glob patterns, matched like --include",
+            false,
+            false,
+            true,
+            true,
+        ),
+        arg_from_id(
+            ARG_ID_MAX_DEPTH,
+            None,
+            "MAX-DEPTH",
+            "Set how many levels of subdirectories to look for images in, 0 only using the input directory",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_BACK_MATTER_PAGES,
+            None,
+            "BACK-MATTER-PAGES",
+            "Set the number of pages at the end of the book holding back matter such as credits",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_READ_AHEAD,
+            None,
+            "READ-AHEAD",
+            "Set the maximum number of images read and analysed in parallel ahead of being added to the book (default 8)",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_COMPRESSION,
+            None,
+            "COMPRESSION",
+            "Set which entries are compressed: store-images (default), deflate-all or store-all (epub only)",
+            false,
+            false,
+            true,
+            false,
+        )
+        .possible_values([
+            COMPRESSION_STORE_IMAGES,
+            COMPRESSION_DEFLATE_ALL,
+            COMPRESSION_STORE_ALL,
+        ]),
+        arg_from_id(
+            ARG_ID_COMPRESSION_LEVEL,
+            None,
+            "COMPRESSION-LEVEL",
+            "Set the deflate level, from 0 (fastest) to 9 (smallest) (epub only)",
+            false,
+            false,
+            true,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_NO_ZIP64,
+            None,
+            "NO-ZIP64",
+            "Fail instead of writing ZIP64 records for books over 4 GiB or 65535 files, for readers not supporting them (epub only)",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_PAGE_TEMPLATES,
+            None,
+            "PAGE-TEMPLATES",
+            "Set a folder with page templates replacing the built-in ones (epub only)",
+            false,
+            false,
+            true,
+            false,
+        ),
         arg_from_id(
             ARG_ID_INPUT,
             None,
@@ -176,7 +661,7 @@ fn main() {
             ARG_ID_OUTPUT,
             None,
             "OUTPUT DIRECTORY",
-            "Set the output folder",
+            "Set the output folder, or - to write the book to standard output",
             true,
             true,
             true,
@@ -205,14 +690,49 @@ fn main() {
             true,
             false,
         ),
+        arg_from_id(
+            ARG_ID_BATCH_JOBS,
+            Some('j'),
+            "JOBS",
+            "Set the number of volumes to generate concurrently, 0 to use all available cores (default 1)",
+            false,
+            false,
+            true,
+            false,
+        ),
     ];
 
-    let matches = Command::new("Comic ePub maker")
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("Create single ePub from images in a directory")
-        .args(&main_args)
-        .subcommand(
+    let watch_args = [
+        arg_from_id(
+            ARG_ID_WATCH_BATCH,
+            None,
+            "BATCH",
+            "Watch a batch input directory, regenerating only volumes whose directory changed",
+            false,
+            false,
+            false,
+            false,
+        ),
+        arg_from_id(
+            ARG_ID_WATCH_SETTLE,
+            None,
+            "SETTLE",
+            "Set how many milliseconds to wait without further changes before regenerating (default 1000)",
+            false,
+            false,
+            true,
+            false,
+        ),
+        batch_args[0].clone(),
+        batch_args[1].clone(),
+    ];
+
+    let matches = Command::new("Comic ePub maker")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Create single ePub from images in a directory")
+        .args(&main_args)
+        .subcommand(
             Command::new(CMD_ID_BATCH)
                 .version(crate_version!())
                 .author(crate_authors!())
@@ -221,12 +741,22 @@ fn main() {
                 )
                 .args(&batch_args),
         )
+        .subcommand(
+            Command::new(CMD_ID_WATCH)
+                .version(crate_version!())
+                .author(crate_authors!())
+                .about("Regenerate ePubs whenever images in the input directory change")
+                .args(&watch_args),
+        )
         .get_matches();
 
     match matches.subcommand() {
         Some((CMD_ID_BATCH, batch_matches)) => {
             generate_batch(&matches, batch_matches).unwrap();
         }
+        Some((CMD_ID_WATCH, watch_matches)) => {
+            watch(&matches, watch_matches).unwrap();
+        }
         Some(_) => panic!("Unrecognized parsed command. This should not happen"),
         None => {
             generate_single(&matches).unwrap();
@@ -236,27 +766,108 @@ fn main() {
 
 pub fn generate_single(args: &ArgMatches) -> Result<()> {
     let (inpath, outpath) = io_directories_from_args(args)?;
-    let metadata = metadata_from_args(args)?;
-    create_epub_file(&metadata, &inpath, &outpath)?;
+    let metadata = match args.value_of(ARG_ID_APPEND) {
+        Some(d) => {
+            if args.value_of(ARG_ID_FORMAT).unwrap_or(FORMAT_EPUB) != FORMAT_EPUB {
+                return Err(anyhow!("Pages can only be appended to ePubs"));
+            }
+            if outpath == Path::new(OUTPUT_STDOUT) {
+                return Err(anyhow!(
+                    "Books with appended pages cannot be written to standard output, specify an output directory"
+                ));
+            }
+
+            let existing = File::open(d).with_context(|| format!("Unable to open {}", d))?;
+            let existing_metadata = Metadata::from_epub(BufReader::new(existing))
+                .with_context(|| format!("Unable to read metadata from {}", d))?;
+            metadata_from_args_with_base(args, existing_metadata)?
+        }
+        None => metadata_from_args(args)?,
+    };
+    create_epub_file(args, &metadata, &inpath, &outpath, true, None)?;
     return Ok(());
 }
 
 pub fn generate_batch(args: &ArgMatches, batch_args: &ArgMatches) -> Result<()> {
+    if args.is_present(ARG_ID_APPEND) {
+        return Err(anyhow!("Pages can only be appended to a single book"));
+    }
+
     let (inpath, outpath) = io_directories_from_args(args)?;
+    if outpath == Path::new(OUTPUT_STDOUT) {
+        return Err(anyhow!(
+            "Batches cannot be written to standard output, specify an output directory"
+        ));
+    }
+
+    let volumes = batch_volumes(args, batch_args, &inpath, &outpath)?;
+    if volumes.is_empty() {
+        println!("No directories to create volumes from found. Aborting");
+        return Ok(());
+    }
+
+    let mut jobs = 1usize;
+    if let Some(jobs_str) = batch_args.value_of(ARG_ID_BATCH_JOBS) {
+        match atoi::atoi::<usize>(jobs_str.as_bytes()) {
+            Some(jobs_usize) => jobs = jobs_usize,
+            None => println!(
+                "Unable to parse number of concurrent jobs. Defaulting to {}",
+                jobs
+            ),
+        }
+    }
+    if jobs == 0 {
+        jobs = std::thread::available_parallelism().map_or(1, |d| d.get());
+    }
+
+    let results = match jobs {
+        1 => volumes
+            .iter()
+            .map(|(vol_dir, metadata)| {
+                create_epub_file(args, metadata, vol_dir, &outpath, true, None)
+            })
+            .collect::<Vec<_>>(),
+        _ => generate_volumes_concurrently(args, &volumes, &outpath, jobs),
+    };
+
+    let failed_count = results.iter().filter(|d| d.is_err()).count();
+    println!(
+        "Batch complete: {} succeeded, {} failed",
+        results.len() - failed_count,
+        failed_count
+    );
+    for ((_, metadata), result) in volumes.iter().zip(results.iter()) {
+        match result {
+            anyhow::Result::Ok(()) => println!("  OK      {}", metadata.title),
+            Err(d) => println!("  FAILED  {}: {:#}", metadata.title, d),
+        }
+    }
+
+    if failed_count > 0 {
+        return Err(anyhow!("{} volumes failed", failed_count));
+    }
+    return Ok(());
+}
+
+/// Volume directories of a batch, in order, with their metadata and generated titles.
+/// The output directory is not a volume when inside the input directory.
+fn batch_volumes(
+    args: &ArgMatches,
+    batch_args: &ArgMatches,
+    inpath: &Path,
+    outpath: &Path,
+) -> Result<Vec<(PathBuf, Metadata)>> {
     let mut metadata = metadata_from_args(args)?;
     metadata.series = Some(metadata.title.clone());
 
+    let outpath = outpath.canonicalize()?;
     let mut vol_dirs = inpath
         .read_dir()?
         .map(|d| d.unwrap().path())
-        .filter(|d| d.is_dir())
+        .filter(|d| d.is_dir() && !is_hidden_or_system(d))
+        .filter(|d| d.canonicalize().is_ok_and(|d| d != outpath))
         .collect::<Vec<_>>();
-    vol_dirs.sort();
-
-    if vol_dirs.is_empty() {
-        println!("No directories to create volumes from found. Aborting");
-        return Ok(());
-    }
+    sort_paths(&mut vol_dirs, !args.is_present(ARG_ID_BYTE_ORDER_SORT));
 
     let title_pattern = metadata.title.clone();
 
@@ -285,7 +896,8 @@ pub fn generate_batch(args: &ArgMatches, batch_args: &ArgMatches) -> Result<()>
     }
     let vol_ctr_fmt_string = format!("0{vol_ctr_num_digits}");
 
-    for vol_dir in vol_dirs.iter() {
+    let mut volumes = Vec::<(PathBuf, Metadata)>::new();
+    for vol_dir in vol_dirs.into_iter() {
         let formatted_vol_number = num_runtime_fmt::NumFmt::from_str(vol_ctr_fmt_string.as_str())
             .unwrap()
             .fmt(vol_ctr)
@@ -295,68 +907,267 @@ pub fn generate_batch(args: &ArgMatches, batch_args: &ArgMatches) -> Result<()>
         if title_pattern.matches(VOLUME_NUMBER_PLACEHOLDER).count() > 0 {
             metadata.title = title_pattern.replace(
                 VOLUME_NUMBER_PLACEHOLDER,
-                format!("{}", formatted_vol_number).as_str(),
+                formatted_vol_number.to_string().as_str(),
             );
         } else {
             metadata.title = format!("{} vol. {}", title_pattern, formatted_vol_number);
         }
 
-        create_epub_file(&metadata, &vol_dir, &outpath)?;
+        volumes.push((vol_dir, metadata.clone()));
     }
 
-    return Ok(());
+    return Ok(volumes);
+}
+
+/// Regenerates books whenever images change, until interrupted. In batch mode only volumes
+/// whose directory changed are regenerated, unless volumes were added or removed.
+/// Unchanged images are read again, but not analysed again.
+pub fn watch(args: &ArgMatches, watch_args: &ArgMatches) -> Result<()> {
+    if args.is_present(ARG_ID_APPEND) {
+        return Err(anyhow!("Pages cannot be appended to watched books"));
+    }
+
+    let (inpath, outpath) = io_directories_from_args(args)?;
+    if outpath == Path::new(OUTPUT_STDOUT) {
+        return Err(anyhow!(
+            "Watched books cannot be written to standard output, specify an output directory"
+        ));
+    }
+    let (inpath, outpath) = (inpath.canonicalize()?, outpath.canonicalize()?);
+
+    let mut settle_ms = DEFAULT_WATCH_SETTLE_MS;
+    if let Some(settle_str) = watch_args.value_of(ARG_ID_WATCH_SETTLE) {
+        match atoi::atoi::<u64>(settle_str.as_bytes()) {
+            Some(settle_u64) => settle_ms = settle_u64,
+            None => eprintln!(
+                "Unable to parse settle time. Defaulting to {} ms",
+                settle_ms
+            ),
+        }
+    }
+
+    // Books written inside the input directory must not trigger further rebuilds: the output
+    // directory is not watched when below the input one, only books are ignored otherwise
+    let output_subdir = outpath.starts_with(&inpath) && outpath != inpath;
+    let ignored_outpath = outpath.clone();
+    let ignore = move |d: &Path| match output_subdir {
+        true => d.starts_with(&ignored_outpath),
+        false => d.parent() == Some(ignored_outpath.as_path()) && is_book_file(d),
+    };
+    let mut watcher = watcher::DirWatcher::new(
+        &inpath,
+        Box::new(move |d| ignore(d) || is_hidden_or_system(d)),
+    )?;
+
+    let cache = ImageCache::default();
+    let mut previous_vol_dirs = Vec::<PathBuf>::new();
+    let mut changed_paths: Option<Vec<PathBuf>> = None;
+    loop {
+        let volumes = match watch_args.is_present(ARG_ID_WATCH_BATCH) {
+            true => batch_volumes(args, watch_args, &inpath, &outpath)?,
+            false => vec![(inpath.clone(), metadata_from_args(args)?)],
+        };
+        let vol_dirs = volumes.iter().map(|(d, _)| d.clone()).collect::<Vec<_>>();
+        if vol_dirs != previous_vol_dirs {
+            changed_paths = None;
+        }
+
+        for (vol_dir, metadata) in volumes.iter() {
+            // Changes to directories containing volumes, like the input directory when the
+            // watcher lost track of events, affect all of them
+            let affected = changed_paths.as_ref().is_none_or(|d| {
+                d.iter()
+                    .any(|d| d.starts_with(vol_dir) || vol_dir.starts_with(d))
+            });
+            if !affected {
+                continue;
+            }
+
+            let result = create_epub_file(args, metadata, vol_dir, &outpath, true, Some(&cache));
+            if let Err(d) = result {
+                eprintln!("Failed {}: {:#}", metadata.title, d);
+            }
+        }
+        cache.remove_missing();
+        previous_vol_dirs = vol_dirs;
+
+        println!("Watching {} for changes", inpath.to_str().unwrap());
+        changed_paths = Some(watcher.wait_for_changes(Duration::from_millis(settle_ms))?);
+    }
 }
 
+/// Generates volumes on `jobs` worker threads, returning results in volume order.
+/// Progress is reported one line per event, so that concurrent volumes don't overwrite each other.
+fn generate_volumes_concurrently(
+    args: &ArgMatches,
+    volumes: &[(PathBuf, Metadata)],
+    output_dir_path: &Path,
+    jobs: usize,
+) -> Vec<Result<()>> {
+    let next_volume = AtomicUsize::new(0);
+    let results = Mutex::new(
+        volumes
+            .iter()
+            .map(|_| Err(anyhow!("Not generated")))
+            .collect::<Vec<_>>(),
+    );
+
+    std::thread::scope(|scope| {
+        for _ in 0..usize::min(jobs, volumes.len()) {
+            scope.spawn(|| loop {
+                let index = next_volume.fetch_add(1, Ordering::SeqCst);
+                if index >= volumes.len() {
+                    break;
+                }
+
+                let (vol_dir, metadata) = &volumes[index];
+                let result =
+                    create_epub_file(args, metadata, vol_dir, output_dir_path, false, None);
+                match result.as_ref() {
+                    anyhow::Result::Ok(()) => println!("Finished {}", metadata.title),
+                    Err(_) => println!("Failed {}", metadata.title),
+                }
+                results.lock().unwrap()[index] = result;
+            });
+        }
+    });
+
+    return results.into_inner().unwrap();
+}
+
+/// Generates a book from images in `input_dir_path`. Interactive progress is updated in place
+/// on a single line, otherwise only the start of generation is reported.
 fn create_epub_file(
+    args: &ArgMatches,
     metadata: &Metadata,
     input_dir_path: &Path,
     output_dir_path: &Path,
+    interactive_progress: bool,
+    cache: Option<&ImageCache>,
 ) -> Result<()> {
     fn create_epub_inner(
+        args: &ArgMatches,
         metadata: &Metadata,
         input_dir_path: &Path,
-        output_file_path: &Path,
+        mut writer: Box<dyn BookWriter>,
+        interactive_progress: bool,
+        cache: Option<&ImageCache>,
     ) -> Result<()> {
-        let f = File::create(output_file_path)?;
-        let f = BufWriter::new(f);
-        let mut writer = EpubWriter::new(f, metadata.clone())?;
-
-        let image_paths = list_supported_images(input_dir_path)?;
-        println!(" ({} images)", image_paths.len());
-
-        let mut cover_set = false;
-        let mut ctr = 0;
-        for image_path in image_paths.iter() {
-            let mut file = BufReader::new(File::open(image_path)?);
-            if cover_set {
-                writer.add_image(&mut file, None).with_context(|| {
-                    format!("Error adding page {}", image_path.to_str().unwrap())
+        let discovery_options = image_discovery_options_from_args(args);
+        let image_paths = list_supported_images(input_dir_path, &discovery_options)?;
+        let (image_paths, back_cover_path) = split_back_cover(args, input_dir_path, image_paths)?;
+        // Books being appended to already have a cover, all images become pages
+        let appending = args.is_present(ARG_ID_APPEND);
+        let mut image_paths = match appending {
+            true => image_paths,
+            false => cover_first(args, image_paths)?,
+        };
+        let page_count = image_paths.len();
+        image_paths.extend(back_cover_path);
+        if interactive_progress {
+            println!(" ({} images)", image_paths.len());
+            writer.set_observer(progress_observer(image_paths.len()));
+        }
+
+        let page_numbers_from_file_names = args.is_present(ARG_ID_PAGE_NUMBERS_FROM_FILE_NAMES);
+        let cover_from_spread = args.is_present(ARG_ID_COVER_FROM_SPREAD);
+        let append_chapter = args.value_of(ARG_ID_APPEND_CHAPTER).map(|d| d.to_string());
+        let right_to_left = metadata.right_to_left;
+        let mut back_matter_pages = 0usize;
+        if let Some(bmp_str) = args.value_of(ARG_ID_BACK_MATTER_PAGES) {
+            match atoi::atoi::<usize>(bmp_str.as_bytes()) {
+                Some(bmp_usize) => back_matter_pages = bmp_usize,
+                None => eprintln!("Unable to parse number of back matter pages. Ignoring"),
+            }
+        }
+
+        let mut read_ahead = DEFAULT_READ_AHEAD;
+        if let Some(ra_str) = args.value_of(ARG_ID_READ_AHEAD) {
+            match atoi::atoi::<usize>(ra_str.as_bytes()) {
+                Some(ra_usize) if ra_usize > 0 => read_ahead = ra_usize,
+                _ => eprintln!(
+                    "Unable to parse number of images to read ahead. Defaulting to {}",
+                    read_ahead
+                ),
+            }
+        }
+
+        prepare_images_in_order(&image_paths, read_ahead, cache, |ctr, image| {
+            let image_path = &image_paths[ctr];
+            if ctr >= page_count {
+                writer.set_prepared_back_cover(image).with_context(|| {
+                    format!("Error adding back cover {}", image_path.to_str().unwrap())
                 })?;
+            } else if ctr > 0 || appending {
+                let mut page_options = PageOptions {
+                    nav_label: match ctr {
+                        0 => append_chapter.clone(),
+                        _ => None,
+                    },
+                    back_matter: ctr + back_matter_pages >= page_count,
+                    ..Default::default()
+                };
+                if page_numbers_from_file_names {
+                    page_options.page_number = page_number_from_file_name(image_path);
+                }
+
+                writer
+                    .add_prepared_image(image, page_options)
+                    .with_context(|| {
+                        format!("Error adding page {}", image_path.to_str().unwrap())
+                    })?;
             } else {
-                writer.set_cover(&mut file).with_context(|| {
+                let image = match cover_from_spread && image.is_spread() {
+                    true => image.spread_half(!right_to_left).with_context(|| {
+                        format!("Error cropping cover {}", image_path.to_str().unwrap())
+                    })?,
+                    false => image,
+                };
+                writer.set_prepared_cover(image).with_context(|| {
                     format!("Error adding cover {}", image_path.to_str().unwrap())
                 })?;
-                cover_set = true;
             }
 
-            ctr += 1;
-            print!(
-                "{:4.1}% complete\r",
-                (100 * ctr) as f32 / image_paths.len() as f32
-            );
-        }
+            return Ok(());
+        })?;
 
-        println!("");
         writer.finalize()?;
         return Ok(());
     }
 
+    if output_dir_path == Path::new(OUTPUT_STDOUT) {
+        let inner = BufWriter::new(std::io::stdout());
+        let writer = streaming_book_writer_from_args(args, inner, metadata.clone())?;
+        return create_epub_inner(args, metadata, input_dir_path, writer, false, cache);
+    }
+
+    let format = args.value_of(ARG_ID_FORMAT).unwrap_or(FORMAT_EPUB);
     let mut output_file_path = PathBuf::from(output_dir_path);
-    output_file_path.push(format!("{}.epub", metadata.title));
-    print!("Generating {}", output_file_path.to_str().unwrap());
+    output_file_path.push(format!("{}.{}", metadata.title, format));
+    if interactive_progress {
+        print!("Generating {}", output_file_path.to_str().unwrap());
+    } else {
+        println!("Generating {}", output_file_path.to_str().unwrap());
+    }
 
-    let temp_path = PathBuf::from(format!("{}.epubgen", output_file_path.to_str().unwrap()));
-    match create_epub_inner(&metadata, &input_dir_path, &temp_path) {
+    let temp_path = PathBuf::from(format!(
+        "{}.{}",
+        output_file_path.to_str().unwrap(),
+        TEMP_EXTENSION
+    ));
+    match File::create(&temp_path)
+        .map_err(anyhow::Error::from)
+        .and_then(|d| book_writer_from_args(args, BufWriter::new(d), metadata.clone()))
+        .and_then(|d| {
+            create_epub_inner(
+                args,
+                metadata,
+                input_dir_path,
+                d,
+                interactive_progress,
+                cache,
+            )
+        }) {
         anyhow::Result::Ok(()) => {
             std::fs::rename(&temp_path, &output_file_path)?;
             return Ok(());
@@ -370,36 +1181,461 @@ fn create_epub_file(
     }
 }
 
-fn list_supported_images(input_dir_path: &Path) -> Result<Vec<PathBuf>> {
-    static SUPPORTED_EXTENSIONS: [&'static str; 4] = [".gif", ".jpeg", ".jpg", ".png"];
+/// Takes the image matching the back cover pattern, if any, out of the list
+fn split_back_cover(
+    args: &ArgMatches,
+    input_dir_path: &Path,
+    mut image_paths: Vec<PathBuf>,
+) -> Result<(Vec<PathBuf>, Option<PathBuf>)> {
+    let pattern = match args.value_of(ARG_ID_BACK_COVER) {
+        Some(d) => vec![d.to_lowercase()],
+        None => return Ok((image_paths, None)),
+    };
+
+    let index = image_paths.iter().rposition(|d| {
+        let relative = d.strip_prefix(input_dir_path).unwrap_or(d);
+        return matches_any_glob(&pattern, relative);
+    });
+    match index {
+        Some(d) => {
+            let back_cover_path = image_paths.remove(d);
+            return Ok((image_paths, Some(back_cover_path)));
+        }
+        None => {
+            return Err(anyhow!(
+                "No image matching back cover pattern {} found",
+                pattern[0]
+            ))
+        }
+    }
+}
+
+/// Moves the image selected as cover to the front of the list, or adds the cover file
+/// in front of it. The cover is kept among pages too if they should include it.
+fn cover_first(args: &ArgMatches, mut image_paths: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let cover_index = if let Some(d) = args.value_of(ARG_ID_COVER_FILE) {
+        let cover_path = PathBuf::from(d);
+        if !cover_path.is_file() {
+            return Err(anyhow!("Cover file {} does not exist", d));
+        }
+        image_paths.insert(0, cover_path);
+        None
+    } else if let Some(d) = args.value_of(ARG_ID_COVER_INDEX) {
+        match atoi::atoi::<usize>(d.as_bytes()) {
+            Some(ci_usize) if ci_usize > 0 && ci_usize <= image_paths.len() => Some(ci_usize - 1),
+            _ => {
+                return Err(anyhow!(
+                    "Cover index must be a number between 1 and the number of images ({})",
+                    image_paths.len()
+                ))
+            }
+        }
+    } else if args.is_present(ARG_ID_COVER_NAMED) {
+        let index = image_paths.iter().position(|d| {
+            d.file_stem()
+                .is_some_and(|d| d.to_string_lossy().eq_ignore_ascii_case("cover"))
+        });
+        match index {
+            Some(d) => Some(d),
+            None => return Err(anyhow!("No image named cover found")),
+        }
+    } else if image_paths.is_empty() {
+        return Ok(image_paths);
+    } else {
+        Some(0)
+    };
+
+    let cover_as_page = args.is_present(ARG_ID_COVER_AS_PAGE);
+    match cover_index {
+        Some(d) if cover_as_page => image_paths.insert(0, image_paths[d].clone()),
+        Some(d) => {
+            let cover_path = image_paths.remove(d);
+            image_paths.insert(0, cover_path);
+        }
+        None if cover_as_page => image_paths.insert(1, image_paths[0].clone()),
+        None => {}
+    }
+
+    return Ok(image_paths);
+}
+
+/// Rules deciding which files under the input directory are added as pages
+struct ImageDiscoveryOptions {
+    natural_sort: bool,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    max_depth: Option<usize>,
+}
+
+fn image_discovery_options_from_args(args: &ArgMatches) -> ImageDiscoveryOptions {
+    fn patterns(args: &ArgMatches, arg_id: &str) -> Vec<String> {
+        return args
+            .values_of(arg_id)
+            .map(|d| d.map(|d| d.to_lowercase()).collect())
+            .unwrap_or_default();
+    }
+
+    let mut max_depth = None;
+    if let Some(md_str) = args.value_of(ARG_ID_MAX_DEPTH) {
+        match atoi::atoi::<usize>(md_str.as_bytes()) {
+            Some(md_usize) => max_depth = Some(md_usize),
+            None => eprintln!("Unable to parse maximum subdirectory depth. Ignoring"),
+        }
+    }
+
+    return ImageDiscoveryOptions {
+        natural_sort: !args.is_present(ARG_ID_BYTE_ORDER_SORT),
+        include: patterns(args, ARG_ID_INCLUDE),
+        exclude: patterns(args, ARG_ID_EXCLUDE),
+        max_depth,
+    };
+}
+
+/// Lists images under `input_dir_path` in reading order, files in a directory coming before
+/// those in its subdirectories. Images are recognised by their content rather than extension.
+fn list_supported_images(
+    input_dir_path: &Path,
+    options: &ImageDiscoveryOptions,
+) -> Result<Vec<PathBuf>> {
+    fn list_inner(
+        dir_path: &Path,
+        relative_path: &Path,
+        depth: usize,
+        options: &ImageDiscoveryOptions,
+        output: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        let mut dir_paths = dir_path
+            .read_dir()?
+            .map(|d| d.unwrap().path())
+            .filter(|d| !is_hidden_or_system(d))
+            .collect::<Vec<_>>();
+        sort_paths(&mut dir_paths, options.natural_sort);
+
+        let mut subdir_paths = Vec::<(PathBuf, PathBuf)>::new();
+        for i in dir_paths.into_iter() {
+            let relative = relative_path.join(i.file_name().unwrap());
+            if matches_any_glob(&options.exclude, &relative) {
+                continue;
+            }
+
+            if i.is_file() {
+                let included =
+                    options.include.is_empty() || matches_any_glob(&options.include, &relative);
+                if included && is_supported_image(&i)? {
+                    output.push(i);
+                }
+            } else if i.is_dir() {
+                subdir_paths.push((i, relative));
+            }
+        }
+
+        if options.max_depth.is_some_and(|d| depth >= d) {
+            return Ok(());
+        }
+        for (i, relative) in subdir_paths.into_iter() {
+            list_inner(&i, &relative, depth + 1, options, output)?;
+        }
+
+        return Ok(());
+    }
 
     let mut output = Vec::<PathBuf>::new();
+    list_inner(input_dir_path, Path::new(""), 0, options, &mut output)?;
+    return Ok(output);
+}
 
-    let mut dir_paths = input_dir_path
-        .read_dir()?
-        .map(|d| d.unwrap().path())
-        .collect::<Vec<_>>();
-    dir_paths.sort();
+/// Checks the file starts with the signature of a format books can hold
+fn is_supported_image(path: &Path) -> Result<bool> {
+    let mut header = Vec::<u8>::with_capacity(16);
+    File::open(path)
+        .and_then(|d| d.take(16).read_to_end(&mut header))
+        .with_context(|| format!("Unable to read {}", path.display()))?;
 
-    let mut subdir_paths = Vec::<PathBuf>::new();
-    for i in dir_paths.into_iter() {
-        if i.is_file() {
-            if SUPPORTED_EXTENSIONS
-                .iter()
-                .any(|&e| i.to_str().unwrap().ends_with(e))
-            {
-                output.push(i);
+    return Ok(image::guess_format(&header).is_ok_and(|d| {
+        matches!(
+            d,
+            image::ImageFormat::Gif | image::ImageFormat::Jpeg | image::ImageFormat::Png
+        )
+    }));
+}
+
+/// Files with the extension of a book format or of books being written
+fn is_book_file(path: &Path) -> bool {
+    let formats = [
+        FORMAT_EPUB,
+        FORMAT_AZW3,
+        FORMAT_CBZ,
+        FORMAT_PDF,
+        TEMP_EXTENSION,
+    ];
+    return path
+        .extension()
+        .is_some_and(|d| formats.iter().any(|f| d.eq_ignore_ascii_case(f)));
+}
+
+/// Dot files, like macOS resource forks, and folders created by operating systems
+fn is_hidden_or_system(path: &Path) -> bool {
+    static SYSTEM_NAMES: [&str; 3] = ["__macosx", "$recycle.bin", "system volume information"];
+
+    let name = match path.file_name() {
+        Some(d) => d.to_string_lossy().to_lowercase(),
+        None => return false,
+    };
+    return name.starts_with('.') || SYSTEM_NAMES.contains(&name.as_str());
+}
+
+/// Matches patterns containing '/' against the whole relative path, others against its last component.
+/// Matching is case insensitive, patterns being lowercased beforehand.
+fn matches_any_glob(patterns: &[String], relative_path: &Path) -> bool {
+    let path = relative_path
+        .to_string_lossy()
+        .replace('\\', "/")
+        .to_lowercase();
+    let name = path.rsplit('/').next().unwrap_or_default();
+    return patterns.iter().any(|d| match d.contains('/') {
+        true => glob_match(d, &path),
+        false => glob_match(d, name),
+    });
+}
+
+/// Glob matching supporting `?` and `*`, which stop at '/', and `**`, which doesn't
+fn glob_match(pattern: &str, text: &str) -> bool {
+    fn match_inner(pattern: &[char], text: &[char]) -> bool {
+        match pattern {
+            [] => text.is_empty(),
+            ['*', '*', '/', rest @ ..] => {
+                match_inner(rest, text)
+                    || (0..text.len())
+                        .filter(|&i| text[i] == '/')
+                        .any(|i| match_inner(rest, &text[i + 1..]))
             }
-        } else {
-            subdir_paths.push(i);
+            ['*', '*', rest @ ..] => (0..=text.len()).any(|i| match_inner(rest, &text[i..])),
+            ['*', rest @ ..] => (0..=text.len())
+                .take_while(|&i| i == 0 || text[i - 1] != '/')
+                .any(|i| match_inner(rest, &text[i..])),
+            ['?', rest @ ..] => {
+                text.first().is_some_and(|&d| d != '/') && match_inner(rest, &text[1..])
+            }
+            [c, rest @ ..] => text.first() == Some(c) && match_inner(rest, &text[1..]),
         }
     }
 
-    for i in subdir_paths.into_iter() {
-        output.append(&mut list_supported_images(&i)?);
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+    return match_inner(&pattern, &text);
+}
+
+/// Sorts paths sharing a parent directory, either by byte value or naturally,
+/// comparing runs of digits by their numeric value so that `page2` comes before `page10`
+fn sort_paths(paths: &mut [PathBuf], natural_sort: bool) {
+    if !natural_sort {
+        paths.sort();
+        return;
     }
 
-    return Ok(output);
+    paths.sort_by(|a, b| {
+        let (a, b) = (a.to_string_lossy(), b.to_string_lossy());
+        return natural_cmp(&a, &b).then_with(|| a.cmp(&b));
+    });
+}
+
+fn natural_cmp(a: &str, b: &str) -> std::cmp::Ordering {
+    fn digit_run(chars: &mut std::iter::Peekable<std::str::Chars>) -> String {
+        let mut output = String::new();
+        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+            output.push(d);
+        }
+
+        let leading_zeros = output.len() - output.trim_start_matches('0').len();
+        return output.split_off(leading_zeros);
+    }
+
+    let (mut a_chars, mut b_chars) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a_chars.peek(), b_chars.peek()) {
+            (None, None) => return std::cmp::Ordering::Equal,
+            (None, Some(_)) => return std::cmp::Ordering::Less,
+            (Some(_), None) => return std::cmp::Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, y) = (digit_run(&mut a_chars), digit_run(&mut b_chars));
+                let output = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if output.is_ne() {
+                    return output;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let output = x.cmp(y);
+                if output.is_ne() {
+                    return output;
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+/// Displays progress on a single line updated in place, followed by the output size once finalized
+fn progress_observer(image_count: usize) -> Box<dyn EpubWriterObserver + Send> {
+    fn print_progress(images_added: usize, image_count: usize, bytes_written: u64) {
+        print!(
+            "{:4.1}% complete, {:.1} MiB written\r",
+            (100 * images_added) as f32 / image_count as f32,
+            bytes_written as f32 / (1024 * 1024) as f32
+        );
+    }
+
+    let mut images_added = 0usize;
+    let mut bytes_written = 0u64;
+    return Box::new(move |event: &EpubWriterEvent| match event {
+        EpubWriterEvent::CoverSet { .. }
+        | EpubWriterEvent::BackCoverSet { .. }
+        | EpubWriterEvent::PageAdded { .. } => {
+            images_added += 1;
+            print_progress(images_added, image_count, bytes_written);
+        }
+        EpubWriterEvent::BytesWritten { total } => {
+            bytes_written = *total;
+            print_progress(images_added, image_count, bytes_written);
+        }
+        EpubWriterEvent::Finalizing(FinalizePhase::Started) => println!(),
+        EpubWriterEvent::Finalizing(FinalizePhase::Completed) => {
+            println!(
+                "Done, {:.1} MiB written",
+                bytes_written as f32 / (1024 * 1024) as f32
+            )
+        }
+        _ => {}
+    });
+}
+
+/// Image probes kept between builds in watch mode, so that images are read again but not
+/// analysed again as long as their files keep the same size and modification time
+#[derive(Default)]
+struct ImageCache {
+    entries: Mutex<HashMap<PathBuf, (u64, SystemTime, ImageProbe)>>,
+}
+
+impl ImageCache {
+    fn get_or_read(&self, image_path: &Path) -> Result<PreparedImage> {
+        let file_metadata = std::fs::metadata(image_path)?;
+        let (len, modified) = (file_metadata.len(), file_metadata.modified()?);
+        let probe = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(image_path)
+            .filter(|d| d.0 == len && d.1 == modified)
+            .map(|d| d.2.clone());
+
+        let data = std::fs::read(image_path)?;
+        if let Some(d) = probe {
+            return Ok(PreparedImage::with_probe(data, &d)?);
+        }
+
+        let image = PreparedImage::new(data)?;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(image_path.to_path_buf(), (len, modified, image.probe()));
+        return Ok(image);
+    }
+
+    fn remove_missing(&self) {
+        self.entries.lock().unwrap().retain(|d, _| d.is_file());
+    }
+}
+
+/// Reads and probes images on worker threads, handing them to `consume` in order.
+/// At most `read_ahead` images are in flight past the last consumed one, bounding memory use.
+/// Images are taken from `cache` when given, and added to it once read.
+fn prepare_images_in_order<F>(
+    image_paths: &[PathBuf],
+    read_ahead: usize,
+    cache: Option<&ImageCache>,
+    mut consume: F,
+) -> Result<()>
+where
+    F: FnMut(usize, PreparedImage) -> Result<()>,
+{
+    struct State {
+        next: usize,
+        consumed: usize,
+        cancelled: bool,
+    }
+
+    let state = Mutex::new(State {
+        next: 0,
+        consumed: 0,
+        cancelled: false,
+    });
+    let state_changed = Condvar::new();
+    let workers = std::thread::available_parallelism().map_or(1, |d| d.get());
+
+    return std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(usize, Result<PreparedImage>)>();
+        for _ in 0..usize::min(workers, read_ahead) {
+            let sender = sender.clone();
+            let (state, state_changed) = (&state, &state_changed);
+            scope.spawn(move || loop {
+                let index = {
+                    let mut state = state_changed
+                        .wait_while(state.lock().unwrap(), |d| {
+                            !d.cancelled
+                                && d.next < image_paths.len()
+                                && d.next >= d.consumed + read_ahead
+                        })
+                        .unwrap();
+                    if state.cancelled || state.next >= image_paths.len() {
+                        break;
+                    }
+                    state.next += 1;
+                    state.next - 1
+                };
+
+                let image_path = &image_paths[index];
+                let result = match cache {
+                    Some(d) => d.get_or_read(image_path),
+                    None => File::open(image_path)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|d| Ok(PreparedImage::read(&mut BufReader::new(d))?)),
+                }
+                .with_context(|| format!("Error reading {}", image_path.to_str().unwrap()));
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending = BTreeMap::<usize, Result<PreparedImage>>::new();
+        let mut consume_all = || -> Result<()> {
+            let mut expected = 0usize;
+            while expected < image_paths.len() {
+                let (index, result) = receiver.recv()?;
+                pending.insert(index, result);
+                while let Some(result) = pending.remove(&expected) {
+                    consume(expected, result?)?;
+                    expected += 1;
+                    state.lock().unwrap().consumed = expected;
+                    state_changed.notify_all();
+                }
+            }
+            return Ok(());
+        };
+
+        let output = consume_all();
+        state.lock().unwrap().cancelled = true;
+        state_changed.notify_all();
+        return output;
+    });
+}
+
+fn page_number_from_file_name(path: &Path) -> Option<String> {
+    let stem = path.file_stem()?.to_str()?;
+    let digits_start = stem.trim_end_matches(|d: char| d.is_ascii_digit()).len();
+    let number = atoi::atoi::<u32>(&stem.as_bytes()[digits_start..])?;
+    return Some(number.to_string());
 }
 
 fn io_directories_from_args(args: &ArgMatches) -> Result<(PathBuf, PathBuf)> {
@@ -409,6 +1645,9 @@ fn io_directories_from_args(args: &ArgMatches) -> Result<(PathBuf, PathBuf)> {
     }
 
     let outpath = PathBuf::from(args.value_of(ARG_ID_OUTPUT).unwrap());
+    if outpath == Path::new(OUTPUT_STDOUT) {
+        return Ok((inpath, outpath));
+    }
     if !(outpath.exists() && outpath.is_dir()) {
         return Err(anyhow!("Output path is not a directory or does not exist",));
     }
@@ -417,7 +1656,13 @@ fn io_directories_from_args(args: &ArgMatches) -> Result<(PathBuf, PathBuf)> {
 }
 
 fn metadata_from_args(args: &ArgMatches) -> Result<Metadata> {
-    let mut output = Metadata::default();
+    return metadata_from_args_with_base(args, Metadata::default());
+}
+
+/// Metadata with values set by arguments replacing those of `base`, tags and
+/// accessibility properties being added to its own
+fn metadata_from_args_with_base(args: &ArgMatches, base: Metadata) -> Result<Metadata> {
+    let mut output = base;
 
     if let Some(d) = args.value_of(ARG_ID_TITLE) {
         output.title = d.to_string();
@@ -450,8 +1695,292 @@ fn metadata_from_args(args: &ArgMatches) -> Result<Metadata> {
             output.tags.insert(i.to_string());
         }
     }
+    if let Some(d) = args.values_of(ARG_ID_ACCESS_MODES) {
+        for i in d {
+            output.access_modes.insert(i.to_string());
+        }
+    }
+    if let Some(d) = args.values_of(ARG_ID_ACCESS_MODE_SUFFICIENT) {
+        for i in d {
+            output.access_modes_sufficient.insert(i.to_string());
+        }
+    }
+    if let Some(d) = args.values_of(ARG_ID_ACCESSIBILITY_FEATURES) {
+        for i in d {
+            output.accessibility_features.insert(i.to_string());
+        }
+    }
+    if let Some(d) = args.values_of(ARG_ID_ACCESSIBILITY_HAZARDS) {
+        for i in d {
+            output.accessibility_hazards.insert(i.to_string());
+        }
+    }
+    if let Some(d) = args.value_of(ARG_ID_ACCESSIBILITY_SUMMARY) {
+        output.accessibility_summary = Some(d.to_string());
+    }
 
-    output.right_to_left = args.is_present(ARG_ID_RTL);
+    output.right_to_left |= args.is_present(ARG_ID_RTL);
     output.validate()?;
     return Ok(output);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering;
+
+    fn sorted(names: &[&str], natural_sort: bool) -> Vec<String> {
+        let mut paths = names.iter().map(PathBuf::from).collect::<Vec<_>>();
+        sort_paths(&mut paths, natural_sort);
+        return paths
+            .iter()
+            .map(|d| d.to_string_lossy().to_string())
+            .collect();
+    }
+
+    #[test]
+    fn natural_cmp_compares_numbers_by_value() {
+        assert_eq!(natural_cmp("page2", "page10"), Ordering::Less);
+        assert_eq!(natural_cmp("page10", "page2"), Ordering::Greater);
+        assert_eq!(natural_cmp("Vol 9", "Vol 10"), Ordering::Less);
+        assert_eq!(natural_cmp("page", "page1"), Ordering::Less);
+        assert_eq!(natural_cmp("a1b2", "a1b10"), Ordering::Less);
+    }
+
+    #[test]
+    fn natural_cmp_ignores_leading_zeros() {
+        assert_eq!(natural_cmp("page007", "page10"), Ordering::Less);
+        assert_eq!(natural_cmp("page010", "page9"), Ordering::Greater);
+        assert_eq!(natural_cmp("page0", "page00"), Ordering::Equal);
+        assert_eq!(natural_cmp("page02", "page2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn sort_paths_orders_equal_values_by_padding() {
+        assert_eq!(
+            sorted(&["p1", "p01", "p001", "p2"], true),
+            ["p001", "p01", "p1", "p2"]
+        );
+        assert_eq!(sorted(&["p01b", "p1a"], true), ["p1a", "p01b"]);
+    }
+
+    #[test]
+    fn sort_paths_handles_non_ascii_names() {
+        assert_eq!(natural_cmp("第2話", "第10話"), Ordering::Less);
+        assert_eq!(natural_cmp("été 10", "été 9"), Ordering::Greater);
+        assert_eq!(
+            sorted(&["Ünter 10", "Über 2", "Ünter 2", "Über 10"], true),
+            ["Über 2", "Über 10", "Ünter 2", "Ünter 10"]
+        );
+        // Digits other than ASCII ones are compared as plain characters
+        assert_eq!(natural_cmp("page２", "page１０"), Ordering::Greater);
+    }
+
+    #[test]
+    fn glob_match_wildcards() {
+        assert!(glob_match("*.jpg", "page1.jpg"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*.jpg", "page1.png"));
+        assert!(glob_match("page?.jpg", "page1.jpg"));
+        assert!(!glob_match("page?.jpg", "page10.jpg"));
+        assert!(!glob_match("page?.jpg", "page.jpg"));
+        assert!(glob_match("p*e*1?.jpg", "page10.jpg"));
+    }
+
+    #[test]
+    fn glob_match_single_wildcards_stop_at_separators() {
+        assert!(!glob_match("*.jpg", "extras/page1.jpg"));
+        assert!(!glob_match("extras?page1.jpg", "extras/page1.jpg"));
+        assert!(glob_match("extras/*.jpg", "extras/page1.jpg"));
+        assert!(!glob_match("extras/*.jpg", "extras/old/page1.jpg"));
+    }
+
+    #[test]
+    fn glob_match_double_wildcards() {
+        // At the start
+        assert!(glob_match("**/page1.jpg", "page1.jpg"));
+        assert!(glob_match("**/page1.jpg", "a/b/page1.jpg"));
+        assert!(!glob_match("**/page1.jpg", "a/bpage1.jpg"));
+        // In the middle
+        assert!(glob_match("extras/**/page1.jpg", "extras/page1.jpg"));
+        assert!(glob_match("extras/**/page1.jpg", "extras/a/b/page1.jpg"));
+        assert!(!glob_match("extras/**/page1.jpg", "other/a/page1.jpg"));
+        // At the end
+        assert!(glob_match("extras/**", "extras/a/b/page1.jpg"));
+        assert!(glob_match("extras/**", "extras/"));
+        assert!(!glob_match("extras/**", "extras"));
+        assert!(!glob_match("extras/**", "other/extras/page1.jpg"));
+    }
+
+    #[test]
+    fn matches_any_glob_uses_relative_paths_for_patterns_with_separators() {
+        let patterns = ["extras/*".to_string()];
+        assert!(matches_any_glob(&patterns, Path::new("Extras/page1.jpg")));
+        assert!(!matches_any_glob(
+            &patterns,
+            Path::new("vol1/extras/page1.jpg")
+        ));
+        assert!(!matches_any_glob(&patterns, Path::new("extras")));
+
+        let patterns = ["cover*".to_string(), "**/credits/*".to_string()];
+        assert!(matches_any_glob(&patterns, Path::new("vol1/Cover.JPG")));
+        assert!(matches_any_glob(
+            &patterns,
+            Path::new("vol1/credits/page1.jpg")
+        ));
+        assert!(!matches_any_glob(&patterns, Path::new("credits")));
+    }
+
+    #[test]
+    fn is_hidden_or_system_names() {
+        assert!(is_hidden_or_system(Path::new("input/.DS_Store")));
+        assert!(is_hidden_or_system(Path::new("input/._page1.jpg")));
+        assert!(is_hidden_or_system(Path::new("input/__MACOSX")));
+        assert!(is_hidden_or_system(Path::new("$RECYCLE.BIN")));
+        assert!(is_hidden_or_system(Path::new("System Volume Information")));
+        assert!(!is_hidden_or_system(Path::new("input/page1.jpg")));
+        assert!(!is_hidden_or_system(Path::new(".hidden/page1.jpg")));
+        assert!(!is_hidden_or_system(Path::new("/")));
+    }
+
+    /// Directory tree under the system temporary directory, removed when dropped
+    struct TestTree(PathBuf);
+
+    impl TestTree {
+        fn new(name: &str, files: &[&str]) -> TestTree {
+            const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+
+            let root = std::env::temp_dir().join(format!("cpub-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&root);
+            for i in files {
+                let path = root.join(i);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                let content = match i.ends_with(".txt") {
+                    true => b"not an image".as_slice(),
+                    false => PNG_SIGNATURE,
+                };
+                std::fs::write(path, content).unwrap();
+            }
+            return TestTree(root);
+        }
+
+        fn list(
+            &self,
+            include: &[&str],
+            exclude: &[&str],
+            max_depth: Option<usize>,
+        ) -> Vec<String> {
+            let options = ImageDiscoveryOptions {
+                natural_sort: true,
+                include: include.iter().map(|d| d.to_string()).collect(),
+                exclude: exclude.iter().map(|d| d.to_string()).collect(),
+                max_depth,
+            };
+            return list_supported_images(&self.0, &options)
+                .unwrap()
+                .iter()
+                .map(|d| {
+                    let relative = d.strip_prefix(&self.0).unwrap();
+                    return relative.to_string_lossy().replace('\\', "/");
+                })
+                .collect();
+        }
+    }
+
+    impl Drop for TestTree {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn list_supported_images_by_content() {
+        let tree = TestTree::new(
+            "content",
+            &[
+                "page10.png",
+                "page2.JPEG",
+                "notes.txt",
+                "noextension",
+                ".hidden.png",
+                "__MACOSX/page1.png",
+                "a/page1.png",
+            ],
+        );
+        assert_eq!(
+            tree.list(&[], &[], None),
+            ["noextension", "page2.JPEG", "page10.png", "a/page1.png"]
+        );
+    }
+
+    #[test]
+    fn list_supported_images_include_and_exclude() {
+        let tree = TestTree::new(
+            "globs",
+            &[
+                "page1.png",
+                "cover.png",
+                "extras/page1.png",
+                "extras/old/page1.png",
+                "vol/extras/page1.png",
+            ],
+        );
+        assert_eq!(
+            tree.list(&["page*"], &[], None),
+            [
+                "page1.png",
+                "extras/page1.png",
+                "extras/old/page1.png",
+                "vol/extras/page1.png"
+            ]
+        );
+        assert_eq!(tree.list(&["extras/*"], &[], None), ["extras/page1.png"]);
+        assert_eq!(
+            tree.list(&["**/extras/**"], &[], None),
+            [
+                "extras/page1.png",
+                "extras/old/page1.png",
+                "vol/extras/page1.png"
+            ]
+        );
+        assert_eq!(
+            tree.list(&[], &["extras"], None),
+            ["cover.png", "page1.png"]
+        );
+        assert_eq!(
+            tree.list(&[], &["extras/old"], None),
+            [
+                "cover.png",
+                "page1.png",
+                "extras/page1.png",
+                "vol/extras/page1.png"
+            ]
+        );
+    }
+
+    #[test]
+    fn list_supported_images_max_depth() {
+        let tree = TestTree::new(
+            "depth",
+            &["p0.png", "a/p1.png", "a/b/p2.png", "a/b/c/p3.png"],
+        );
+        assert_eq!(tree.list(&[], &[], Some(0)), ["p0.png"]);
+        assert_eq!(tree.list(&[], &[], Some(1)), ["p0.png", "a/p1.png"]);
+        assert_eq!(
+            tree.list(&[], &[], Some(2)),
+            ["p0.png", "a/p1.png", "a/b/p2.png"]
+        );
+        assert_eq!(
+            tree.list(&[], &[], None),
+            ["p0.png", "a/p1.png", "a/b/p2.png", "a/b/c/p3.png"]
+        );
+    }
+
+    #[test]
+    fn sort_paths_uses_byte_order_when_not_natural() {
+        assert_eq!(
+            sorted(&["page2", "page10", "page1"], false),
+            ["page1", "page10", "page2"]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use std::collections::BTreeSet;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Reports changes to files under a directory once they settle.
+///
+/// Uses inotify on Linux. Elsewhere, the directory tree is polled for files being added,
+/// removed, or changing size or modification time.
+pub struct DirWatcher {
+    events: platform::ChangeEvents,
+    ignore: Box<dyn Fn(&Path) -> bool>,
+}
+
+impl DirWatcher {
+    /// Starts watching `root` and its subdirectories, paths for which `ignore` returns true
+    /// not being watched or reported
+    pub fn new(root: &Path, ignore: Box<dyn Fn(&Path) -> bool>) -> Result<DirWatcher> {
+        let events = platform::ChangeEvents::new(root, ignore.as_ref())?;
+        return Ok(DirWatcher { events, ignore });
+    }
+
+    /// Blocks until something changes, then until nothing else changes for `settle`,
+    /// returning the paths of changed files and directories
+    pub fn wait_for_changes(&mut self, settle: Duration) -> Result<Vec<PathBuf>> {
+        let mut output = BTreeSet::<PathBuf>::new();
+        while output.is_empty() {
+            output.extend(self.next_changes(None)?);
+        }
+
+        loop {
+            let changes = self.next_changes(Some(settle))?;
+            if changes.is_empty() {
+                return Ok(output.into_iter().collect());
+            }
+            output.extend(changes);
+        }
+    }
+
+    /// Changes reported within `timeout`, waiting indefinitely if none is given
+    fn next_changes(&mut self, timeout: Option<Duration>) -> Result<Vec<PathBuf>> {
+        let ignore = self.ignore.as_ref();
+        let changes = self.events.next(timeout, ignore)?;
+        return Ok(changes.into_iter().filter(|d| !ignore(d)).collect());
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use std::collections::HashMap;
+    use std::ffi::{CString, OsStr};
+    use std::io::{Error, ErrorKind, Result};
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    const WATCH_MASK: u32 = libc::IN_CREATE
+        | libc::IN_DELETE
+        | libc::IN_CLOSE_WRITE
+        | libc::IN_MODIFY
+        | libc::IN_MOVED_FROM
+        | libc::IN_MOVED_TO
+        | libc::IN_ATTRIB;
+
+    /// inotify instance with a watch on every directory of the tree
+    pub struct ChangeEvents {
+        fd: OwnedFd,
+        root: PathBuf,
+        watches: HashMap<i32, PathBuf>,
+    }
+
+    impl ChangeEvents {
+        pub fn new(root: &Path, ignore: &dyn Fn(&Path) -> bool) -> Result<ChangeEvents> {
+            // SAFETY: inotify_init1 takes no pointers, the descriptor is owned from here on
+            let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+            if fd < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let mut output = ChangeEvents {
+                fd: unsafe { OwnedFd::from_raw_fd(fd) },
+                root: root.to_path_buf(),
+                watches: HashMap::new(),
+            };
+            output.watch_tree(root, ignore)?;
+            return Ok(output);
+        }
+
+        pub fn next(
+            &mut self,
+            timeout: Option<Duration>,
+            ignore: &dyn Fn(&Path) -> bool,
+        ) -> Result<Vec<PathBuf>> {
+            let timeout_ms = timeout.map_or(-1, |d| d.as_millis().min(i32::MAX as u128) as i32);
+            let mut poll_fd = libc::pollfd {
+                fd: self.fd.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY: poll_fd is a single valid pollfd for the duration of the call
+            match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+                0 => return Ok(Vec::new()),
+                d if d < 0 => {
+                    let error = Error::last_os_error();
+                    return match error.kind() {
+                        ErrorKind::Interrupted => Ok(Vec::new()),
+                        _ => Err(error),
+                    };
+                }
+                _ => {}
+            }
+
+            let mut buffer = vec![0u8; 64 * 1024];
+            // SAFETY: buffer is valid for writes of its whole length
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            let header_len = std::mem::size_of::<libc::inotify_event>();
+            let mut output = Vec::<PathBuf>::new();
+            let mut offset = 0usize;
+            while offset + header_len <= len as usize {
+                // SAFETY: the kernel writes whole events, a header is available at offset
+                let event = unsafe {
+                    std::ptr::read_unaligned(buffer[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + header_len;
+                offset = name_start + event.len as usize;
+
+                // Events were dropped, so anything may have changed, including directories
+                // created without being watched yet
+                if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                    let root = self.root.clone();
+                    self.watch_tree(&root, ignore)?;
+                    output.push(root);
+                    continue;
+                }
+                if event.mask & libc::IN_IGNORED != 0 {
+                    self.watches.remove(&event.wd);
+                    continue;
+                }
+                let dir_path = match self.watches.get(&event.wd) {
+                    Some(d) => d,
+                    None => continue,
+                };
+
+                let name = &buffer[name_start..offset];
+                let name = &name[..name.iter().position(|&d| d == 0).unwrap_or(name.len())];
+                let path = dir_path.join(OsStr::from_bytes(name));
+                let new_dir = event.mask & libc::IN_ISDIR != 0
+                    && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+                if new_dir {
+                    self.watch_tree(&path, ignore)?;
+                }
+                output.push(path);
+            }
+
+            return Ok(output);
+        }
+
+        fn watch_tree(&mut self, dir_path: &Path, ignore: &dyn Fn(&Path) -> bool) -> Result<()> {
+            if ignore(dir_path) {
+                return Ok(());
+            }
+
+            let c_path = CString::new(dir_path.as_os_str().as_bytes())
+                .map_err(|d| Error::new(ErrorKind::InvalidInput, d))?;
+            // SAFETY: c_path is a valid NUL terminated string for the duration of the call
+            let wd = unsafe {
+                libc::inotify_add_watch(self.fd.as_raw_fd(), c_path.as_ptr(), WATCH_MASK)
+            };
+            if wd < 0 {
+                let error = Error::last_os_error();
+                return match already_gone(&error) {
+                    true => Ok(()),
+                    false => Err(error),
+                };
+            }
+            self.watches.insert(wd, dir_path.to_path_buf());
+
+            let entries = match dir_path.read_dir() {
+                Ok(d) => d,
+                Err(d) if already_gone(&d) => return Ok(()),
+                Err(d) => return Err(d),
+            };
+            for i in entries {
+                let path = match i {
+                    Ok(d) => d.path(),
+                    Err(d) if already_gone(&d) => continue,
+                    Err(d) => return Err(d),
+                };
+                if path.is_dir() {
+                    self.watch_tree(&path, ignore)?;
+                }
+            }
+
+            return Ok(());
+        }
+    }
+
+    /// Errors of directories removed or replaced by files while being watched, their removal
+    /// being reported by events of their parent directory
+    fn already_gone(error: &Error) -> bool {
+        return error.kind() == ErrorKind::NotFound || error.raw_os_error() == Some(libc::ENOTDIR);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod platform {
+    use std::collections::HashMap;
+    use std::io::{ErrorKind, Result};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant, SystemTime};
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Sizes and modification times of files in the tree, compared at regular intervals
+    pub struct ChangeEvents {
+        root: PathBuf,
+        files: HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    }
+
+    impl ChangeEvents {
+        pub fn new(root: &Path, ignore: &dyn Fn(&Path) -> bool) -> Result<ChangeEvents> {
+            let mut files = HashMap::new();
+            snapshot(root, ignore, &mut files)?;
+            return Ok(ChangeEvents {
+                root: root.to_path_buf(),
+                files,
+            });
+        }
+
+        pub fn next(
+            &mut self,
+            timeout: Option<Duration>,
+            ignore: &dyn Fn(&Path) -> bool,
+        ) -> Result<Vec<PathBuf>> {
+            let start = Instant::now();
+            loop {
+                std::thread::sleep(POLL_INTERVAL);
+
+                let mut files = HashMap::new();
+                snapshot(&self.root, ignore, &mut files)?;
+                let mut output = files
+                    .iter()
+                    .filter(|(path, state)| self.files.get(*path) != Some(state))
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                output.extend(
+                    self.files
+                        .keys()
+                        .filter(|d| !files.contains_key(*d))
+                        .cloned(),
+                );
+                self.files = files;
+
+                if !output.is_empty() || timeout.is_some_and(|d| start.elapsed() >= d) {
+                    return Ok(output);
+                }
+            }
+        }
+    }
+
+    fn snapshot(
+        dir_path: &Path,
+        ignore: &dyn Fn(&Path) -> bool,
+        output: &mut HashMap<PathBuf, (u64, Option<SystemTime>)>,
+    ) -> Result<()> {
+        // Files and directories can be removed while being listed
+        let entries = match dir_path.read_dir() {
+            Ok(d) => d,
+            Err(d) if d.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(d) => return Err(d),
+        };
+        for i in entries {
+            let path = i?.path();
+            if ignore(&path) {
+                continue;
+            }
+
+            let metadata = match path.metadata() {
+                Ok(d) => d,
+                Err(d) if d.kind() == ErrorKind::NotFound => continue,
+                Err(d) => return Err(d),
+            };
+            if metadata.is_dir() {
+                snapshot(&path, ignore, output)?;
+            } else {
+                output.insert(path, (metadata.len(), metadata.modified().ok()));
+            }
+        }
+
+        return Ok(());
+    }
+}